@@ -1,13 +1,42 @@
-use std::vec;
+use std::{process::ExitCode, vec};
 
-use qftp::auth::{AuthManager, FileStorage};
+use qftp::{
+    auth::{AuthError, AuthManager, FileStorage},
+    Error,
+};
 
 #[tokio::main]
-async fn main() {
-    let file_storage = FileStorage::new("./qftp/auth.json").await.unwrap();
+async fn main() -> ExitCode {
+    let file_storage = match FileStorage::new("./qftp/auth.json").await {
+        Ok(file_storage) => file_storage,
+        Err(e) => {
+            eprintln!("failed to open the auth file: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
     let mut manager = AuthManager::new(file_storage);
-    manager
-        .add_user("test_user".to_string(), "123".to_string(), 501, vec![20])
-        .await
-        .unwrap();
+    let result = manager
+        .add_user(
+            "test_user".to_string(),
+            "123".to_string(),
+            501,
+            vec![20],
+            None,
+        )
+        .await;
+
+    match result {
+        Ok(()) => {
+            println!("added test_user");
+            ExitCode::SUCCESS
+        }
+        Err(Error::AuthenticationError(AuthError::UserExists)) => {
+            println!("test_user already exists, leaving it unchanged");
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("failed to add test_user: {e}");
+            ExitCode::FAILURE
+        }
+    }
 }
@@ -1,27 +1,58 @@
-use std::{fmt, path::Path};
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::IpAddr,
+    os::unix::fs::{self as unix_fs, MetadataExt},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use crate::Error;
 use serde::{Deserialize, Serialize};
 use serde_json;
 use thiserror::Error as ThisError;
 use tokio::{
-    fs::{File, OpenOptions},
-    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    fs::{self, File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::Mutex,
+};
+
+use ring::{
+    digest,
+    signature::{self, ED25519_PUBLIC_KEY_LEN},
 };
+use rustls::Certificate;
 
 use argon2::{
     password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
     Argon2,
 };
 
-// TODO: add prevention of creating double users
+/// Verified instead of the password hash of a user that doesn't exist, so looking it up takes as long as
+/// checking a wrong password. Created by [hash_password] with the default parameters of [Argon2]
+const DUMMY_PASSWORD_HASH: &str =
+    "$argon2id$v=19$m=4096,t=3,p=1$s5bN6DiYe4bHZZ2cLMCVKQ$aSkbea4Wz/uXv1LsmIKxXiQ8cZ/SIAzsNkP6NS76XGw";
+
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStorage;
 
 #[derive(ThisError, Debug)]
 pub enum AuthError {
     #[error("failed to find user")]
     UserNotFound,
+    #[error("a user with this name already exists")]
+    UserExists,
     #[error("failed to validate password")]
     WrongPassword,
+    #[error("the public key isn't authorized or the signature is invalid")]
+    WrongKey,
+    #[error("`{0}` isn't a hex encoded Ed25519 public key")]
+    InvalidKey(String),
+    #[error("too many failed logins, locked out for {0:?}")]
+    LockedOut(Duration),
     #[error("password hash error")]
     PasswordHashError(password_hash::errors::Error),
 }
@@ -33,7 +64,8 @@ impl From<password_hash::errors::Error> for AuthError {
 }
 
 #[async_trait::async_trait]
-pub trait Storage {
+pub trait Storage: fmt::Debug {
+    /// Fails with [AuthError::UserExists] if there already is a user with the same name
     async fn add_user(&mut self, user: User) -> Result<(), Error>;
     async fn get_user<'a>(&mut self, name: &'a str) -> Result<User, Error> {
         let users = self.get_users().await?;
@@ -43,61 +75,391 @@ pub trait Storage {
         }
     }
     async fn get_users(&mut self) -> Result<Vec<User>, Error>;
+    /// Returns the user the client certificate with the [fingerprint](certificate_fingerprint) is mapped to
+    async fn get_user_by_certificate<'a>(&mut self, fingerprint: &'a str) -> Result<User, Error> {
+        let users = self.get_users().await?;
+        match users
+            .into_iter()
+            .find(|u| u.certificates.iter().any(|c| c == fingerprint))
+        {
+            Some(user) => Ok(user),
+            None => Err(AuthError::UserNotFound.into()),
+        }
+    }
+    /// Fails with [AuthError::UserNotFound] if there is no user with this name
+    async fn remove_user<'a>(&mut self, name: &'a str) -> Result<(), Error>;
+    /// Replaces the user with the same name. Fails with [AuthError::UserNotFound] if there is none
+    async fn update_user(&mut self, user: User) -> Result<(), Error>;
+    /// Replaces the password hash of the user
+    async fn set_password<'a>(
+        &mut self,
+        name: &'a str,
+        password_hash: String,
+    ) -> Result<(), Error> {
+        let mut user = self.get_user(name).await?;
+        user.password = password_hash;
+        self.update_user(user).await
+    }
+}
+
+/// Lets a [Server](crate::Server) use any [Storage] without being generic over it
+#[async_trait::async_trait]
+impl<T: Storage + Send + ?Sized> Storage for Box<T> {
+    async fn add_user(&mut self, user: User) -> Result<(), Error> {
+        (**self).add_user(user).await
+    }
+    async fn get_user<'a>(&mut self, name: &'a str) -> Result<User, Error> {
+        (**self).get_user(name).await
+    }
+    async fn get_users(&mut self) -> Result<Vec<User>, Error> {
+        (**self).get_users().await
+    }
+    async fn get_user_by_certificate<'a>(&mut self, fingerprint: &'a str) -> Result<User, Error> {
+        (**self).get_user_by_certificate(fingerprint).await
+    }
+    async fn remove_user<'a>(&mut self, name: &'a str) -> Result<(), Error> {
+        (**self).remove_user(name).await
+    }
+    async fn update_user(&mut self, user: User) -> Result<(), Error> {
+        (**self).update_user(user).await
+    }
+    async fn set_password<'a>(
+        &mut self,
+        name: &'a str,
+        password_hash: String,
+    ) -> Result<(), Error> {
+        (**self).set_password(name, password_hash).await
+    }
+}
+
+/// Keeps the users in memory. Useful for tests, or if the users are managed by the embedding application.
+#[derive(Debug, Default)]
+pub struct InMemoryStorage {
+    users: Vec<User>,
+}
+
+impl InMemoryStorage {
+    pub fn new() -> Self {
+        InMemoryStorage::default()
+    }
+}
+
+impl From<Vec<User>> for InMemoryStorage {
+    fn from(users: Vec<User>) -> Self {
+        InMemoryStorage { users }
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for InMemoryStorage {
+    async fn add_user(&mut self, user: User) -> Result<(), Error> {
+        if self.users.iter().any(|u| u.name == user.name) {
+            return Err(AuthError::UserExists.into());
+        }
+        self.users.push(user);
+        Ok(())
+    }
+
+    async fn get_user<'a>(&mut self, name: &'a str) -> Result<User, Error> {
+        match self.users.iter().find(|u| u.name == name) {
+            Some(user) => Ok(user.clone()),
+            None => Err(AuthError::UserNotFound.into()),
+        }
+    }
+
+    async fn get_users(&mut self) -> Result<Vec<User>, Error> {
+        Ok(self.users.clone())
+    }
+
+    async fn remove_user<'a>(&mut self, name: &'a str) -> Result<(), Error> {
+        let len = self.users.len();
+        self.users.retain(|u| u.name != name);
+        match self.users.len() == len {
+            true => Err(AuthError::UserNotFound.into()),
+            false => Ok(()),
+        }
+    }
+
+    async fn update_user(&mut self, user: User) -> Result<(), Error> {
+        match self.users.iter_mut().find(|u| u.name == user.name) {
+            Some(old) => {
+                *old = user;
+                Ok(())
+            }
+            None => Err(AuthError::UserNotFound.into()),
+        }
+    }
 }
 
+/// Stores the users in a JSON file.
+///
+/// The file is never written in place: a changed document is written to `<path>.tmp`, synced to disk and then
+/// renamed over the old one, so a crash leaves either the old or the new document behind.
+/// Changes hold an exclusive advisory lock on `<path>.lock`, so e.g. the `user` binary and a running server can't
+/// overwrite each other's changes.
 #[derive(Debug)]
 pub struct FileStorage {
-    file: File,
+    path: PathBuf,
 }
 
 impl FileStorage {
     pub async fn new(path: impl AsRef<Path>) -> Result<Self, Error> {
-        let file = OpenOptions::new()
+        let path = path.as_ref().to_owned();
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .await?;
+
+        Ok(FileStorage { path })
+    }
+
+    fn with_suffix(&self, suffix: &str) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(suffix);
+        path.into()
+    }
+
+    /// Takes the advisory lock, it is released when the returned file is dropped
+    async fn lock(&self) -> Result<std::fs::File, Error> {
+        let lock_path = self.with_suffix(".lock");
+        let lock = tokio::task::spawn_blocking(move || {
+            let lock = std::fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(lock_path)?;
+            lock.lock()?;
+            Ok::<_, std::io::Error>(lock)
+        })
+        .await
+        .map_err(std::io::Error::other)??;
+
+        Ok(lock)
+    }
+
+    /// Applies `change` to the stored users while holding the lock
+    async fn change_users<F>(&mut self, change: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut Vec<User>) -> Result<(), Error> + Send,
+    {
+        let _lock = self.lock().await?;
+        let mut users = self.get_users().await?;
+        change(&mut users)?;
+        self.write_users(&users).await
+    }
+
+    async fn write_users(&self, users: &[User]) -> Result<(), Error> {
+        let json = serde_json::to_string(users)?;
+        let tmp_path = self.with_suffix(".tmp");
+        // only the owner can read the password hashes until the permissions of the old file are copied
+        let mut tmp = OpenOptions::new()
             .write(true)
             .create(true)
-            .read(true)
-            .open(path)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp_path)
             .await?;
+        let metadata = fs::metadata(&self.path).await?;
+        let tmp_metadata = tmp.metadata().await?;
+        if (metadata.uid(), metadata.gid()) != (tmp_metadata.uid(), tmp_metadata.gid()) {
+            match unix_fs::chown(&tmp_path, Some(metadata.uid()), Some(metadata.gid())) {
+                // only root can give the file away, it then belongs to whoever changed it
+                Err(e) if e.kind() == io::ErrorKind::PermissionDenied => (),
+                result => result?,
+            }
+        }
+        tmp.set_permissions(metadata.permissions()).await?;
+        tmp.write_all(json.as_bytes()).await?;
+        tmp.sync_all().await?;
+        fs::rename(&tmp_path, &self.path).await?;
 
-        Ok(FileStorage { file })
+        // sync the directory as well, otherwise the rename itself might not survive a crash
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir).await?.sync_all().await?;
+        Ok(())
     }
 }
 
 #[async_trait::async_trait]
 impl Storage for FileStorage {
     async fn add_user(&mut self, user: User) -> Result<(), Error> {
-        let mut users = self.get_users().await?;
-        users.push(user);
-
-        let json = serde_json::to_string(&users)?;
-        self.file.rewind().await?;
-        self.file.write_all(json.as_bytes()).await?;
-        self.file.flush().await?;
-        Ok(())
+        self.change_users(|users| {
+            if users.iter().any(|u| u.name == user.name) {
+                return Err(AuthError::UserExists.into());
+            }
+            users.push(user);
+            Ok(())
+        })
+        .await
     }
 
     async fn get_users(&mut self) -> Result<Vec<User>, Error> {
-        let mut users = String::new();
-        self.file.rewind().await?;
-        self.file.read_to_string(&mut users).await?;
+        // no lock needed, the file is only ever replaced as a whole
+        let users = fs::read_to_string(&self.path).await?;
         match users.is_empty() {
             true => Ok(Vec::new()),
             false => Ok(serde_json::from_str(&users)?),
         }
     }
+
+    async fn remove_user<'a>(&mut self, name: &'a str) -> Result<(), Error> {
+        self.change_users(|users| {
+            let len = users.len();
+            users.retain(|u| u.name != name);
+            match users.len() == len {
+                true => Err(AuthError::UserNotFound.into()),
+                false => Ok(()),
+            }
+        })
+        .await
+    }
+
+    async fn update_user(&mut self, user: User) -> Result<(), Error> {
+        self.change_users(
+            |users| match users.iter_mut().find(|u| u.name == user.name) {
+                Some(old) => {
+                    *old = user;
+                    Ok(())
+                }
+                None => Err(AuthError::UserNotFound.into()),
+            },
+        )
+        .await
+    }
+
+    async fn set_password<'a>(
+        &mut self,
+        name: &'a str,
+        password_hash: String,
+    ) -> Result<(), Error> {
+        self.change_users(|users| match users.iter_mut().find(|u| u.name == name) {
+            Some(user) => {
+                user.password = password_hash;
+                Ok(())
+            }
+            None => Err(AuthError::UserNotFound.into()),
+        })
+        .await
+    }
+}
+
+/// Limits how often logins for a user name or from an address can fail.
+///
+/// Once a user name or an address reached its maximum number of failures, it is locked out for [lockout](LockoutPolicy::lockout).
+/// Every further failure doubles the lockout, up to [max_lockout](LockoutPolicy::max_lockout).
+#[derive(Debug, Clone)]
+pub struct LockoutPolicy {
+    /// Failed logins for a user name before it is locked out. A successful login resets them
+    pub max_user_failures: u32,
+    /// Failed logins from an address before it is locked out. Usually higher, since clients can share an address.
+    /// IPv6 addresses are counted per /64 network
+    pub max_address_failures: u32,
+    pub lockout: Duration,
+    pub max_lockout: Duration,
+    /// Failures are forgotten if there was none for this long
+    pub reset_after: Duration,
+}
+
+impl Default for LockoutPolicy {
+    fn default() -> Self {
+        LockoutPolicy {
+            max_user_failures: 5,
+            max_address_failures: 20,
+            lockout: Duration::from_secs(1),
+            max_lockout: Duration::from_secs(15 * 60),
+            reset_after: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    last_failure: Instant,
+}
+
+impl Failures {
+    /// Failures are forgotten once there was none for [reset_after](LockoutPolicy::reset_after)
+    fn is_forgotten(&self, now: Instant, policy: &LockoutPolicy) -> bool {
+        now.saturating_duration_since(self.last_failure) >= policy.reset_after
+    }
+
+    fn record(&mut self, now: Instant, policy: &LockoutPolicy) {
+        if self.is_forgotten(now, policy) {
+            self.count = 0;
+        }
+        self.count += 1;
+        self.last_failure = now;
+    }
+
+    fn locked_until(&self, max_failures: u32, policy: &LockoutPolicy) -> Option<Instant> {
+        let exponent = self.count.checked_sub(max_failures)?.min(31);
+        let lockout = policy
+            .lockout
+            .saturating_mul(1 << exponent)
+            .min(policy.max_lockout);
+        Some(self.last_failure + lockout)
+    }
+}
+
+/// The failed logins per user name and address
+#[derive(Debug, Default)]
+struct Lockout {
+    users: HashMap<String, Failures>,
+    /// keyed by [address_key]
+    addresses: HashMap<IpAddr, Failures>,
+    /// the number of entries at which the forgotten failures are pruned next
+    prune_at: usize,
+}
+
+/// Prune the forgotten failures once there are this many entries, or twice as many as after the last pruning
+const MIN_PRUNE_AT: usize = 1024;
+
+/// The failures of an address are counted per IPv4 address, but per /64 network for IPv6.
+/// An IPv6 client usually gets a whole /64 and could rotate through it otherwise.
+fn address_key(address: IpAddr) -> IpAddr {
+    match address {
+        IpAddr::V4(_) => address,
+        IpAddr::V6(address) => match address.to_ipv4_mapped() {
+            Some(address) => IpAddr::V4(address),
+            None => IpAddr::V6((u128::from(address) & !u128::from(u64::MAX)).into()),
+        },
+    }
 }
 
 #[derive(Debug)]
 pub struct AuthManager<T: Storage + Send> {
     storage: T,
+    lockout_policy: LockoutPolicy,
+    lockout: Lockout,
 }
 
 impl<T: Storage + Send> AuthManager<T> {
     pub fn new(storage: T) -> Self {
-        AuthManager { storage }
+        AuthManager {
+            storage,
+            lockout_policy: LockoutPolicy::default(),
+            lockout: Lockout::default(),
+        }
+    }
+
+    /// Use `policy` instead of the [default](LockoutPolicy::default) one for [login_password](AuthManager::login_password)
+    /// and [login_public_key](AuthManager::login_public_key)
+    pub fn with_lockout_policy(mut self, policy: LockoutPolicy) -> Self {
+        self.lockout_policy = policy;
+
+        self
     }
 }
 
+/// The [AuthManager] a [Server](crate::Server) shares between all of its clients
+pub(crate) type SharedAuthManager = Arc<Mutex<AuthManager<Box<dyn Storage + Send>>>>;
+
 impl<T: Storage + Send> AuthManager<T> {
     pub async fn add_user(
         &mut self,
@@ -105,43 +467,333 @@ impl<T: Storage + Send> AuthManager<T> {
         password: String,
         uid: u32,
         gid: Vec<u32>,
+        root: Option<PathBuf>,
     ) -> Result<(), Error> {
-        let salt = SaltString::generate(&mut OsRng);
-        // TODO: remove this .unwrap()
-        let password_hash = Argon2::default()
-            .hash_password(password.as_bytes(), &salt)
-            .unwrap()
-            .to_string();
         let user = User {
             name,
-            password: password_hash,
+            password: hash_password(&password)?,
             uid,
             gid,
+            root,
+            certificates: Vec::new(),
+            authorized_keys: Vec::new(),
         };
         self.storage.add_user(user).await?;
 
         Ok(())
     }
 
-    pub async fn get_user<'a>(&mut self, name: &'a str, password: &'a str) -> Result<User, Error> {
+    pub async fn remove_user(&mut self, name: &str) -> Result<(), Error> {
+        self.storage.remove_user(name).await
+    }
+
+    pub async fn set_password(&mut self, name: &str, password: &str) -> Result<(), Error> {
+        self.storage
+            .set_password(name, hash_password(password)?)
+            .await
+    }
+
+    /// Replaces the uid, gid and root of the user, the password stays the same
+    pub async fn update_user(
+        &mut self,
+        name: &str,
+        uid: u32,
+        gid: Vec<u32>,
+        root: Option<PathBuf>,
+    ) -> Result<(), Error> {
+        let user = self.storage.get_user(name).await?;
+        self.storage
+            .update_user(User {
+                uid,
+                gid,
+                root,
+                ..user
+            })
+            .await
+    }
+
+    /// Replaces the client certificates the user is logged in with, see [certificate_fingerprint].
+    /// The fingerprints can also be given in the `AB:CD:..` notation of `openssl x509 -fingerprint -sha256`.
+    pub async fn set_certificates(
+        &mut self,
+        name: &str,
+        fingerprints: Vec<String>,
+    ) -> Result<(), Error> {
+        let user = self.storage.get_user(name).await?;
+        let certificates = fingerprints
+            .iter()
+            .map(|fingerprint| fingerprint.replace(':', "").to_lowercase())
+            .collect();
+        self.storage
+            .update_user(User {
+                certificates,
+                ..user
+            })
+            .await
+    }
+
+    /// Replaces the hex encoded Ed25519 public keys the user can log in with
+    pub async fn set_authorized_keys(
+        &mut self,
+        name: &str,
+        public_keys: Vec<String>,
+    ) -> Result<(), Error> {
+        let mut authorized_keys = Vec::new();
+        for public_key in public_keys {
+            let is_hex = public_key.chars().all(|c| c.is_ascii_hexdigit());
+            if public_key.len() != ED25519_PUBLIC_KEY_LEN * 2 || !is_hex {
+                return Err(AuthError::InvalidKey(public_key).into());
+            }
+            authorized_keys.push(public_key.to_lowercase());
+        }
+
         let user = self.storage.get_user(name).await?;
+        self.storage
+            .update_user(User {
+                authorized_keys,
+                ..user
+            })
+            .await
+    }
+
+    /// Returns the user if `public_key` is one of its authorized keys and `signature` is its signature of `data`.
+    ///
+    /// The signature is verified even if the user doesn't exist, so the time taken doesn't reveal whether it does.
+    pub async fn get_user_by_public_key(
+        &mut self,
+        name: &str,
+        public_key: &[u8],
+        data: &[u8],
+        signature: &[u8],
+    ) -> Result<User, Error> {
+        let user = self.find_user(name).await?;
+        check_public_key(user, public_key, data, signature)
+    }
+
+    /// Like [get_user](AuthManager::get_user), but fails with [AuthError::LockedOut] without checking the password
+    /// if there were too many failed logins for `name` or from `address`
+    pub async fn login_password(
+        &mut self,
+        name: &str,
+        password: &str,
+        address: IpAddr,
+    ) -> Result<User, Error> {
+        let user = self.start_login(name, address).await?;
+        let result = check_password(user, password).await;
+        self.record_login(name, address, &result);
+        result
+    }
+
+    /// Like [get_user_by_public_key](AuthManager::get_user_by_public_key), but fails with [AuthError::LockedOut]
+    /// without checking the signature if there were too many failed logins for `name` or from `address`
+    pub async fn login_public_key(
+        &mut self,
+        name: &str,
+        public_key: &[u8],
+        data: &[u8],
+        signature: &[u8],
+        address: IpAddr,
+    ) -> Result<User, Error> {
+        let user = self.start_login(name, address).await?;
+        let result = check_public_key(user, public_key, data, signature);
+        self.record_login(name, address, &result);
+        result
+    }
+
+    /// The first step of a login: fails with [AuthError::LockedOut] if there were too many failed logins for `name`
+    /// or from `address`, otherwise returns the user or `None` if it doesn't exist.
+    ///
+    /// The credentials are then checked with [check_password] or [check_public_key] and the result is passed to
+    /// [record_login](AuthManager::record_login). The [AuthManager] doesn't have to be held while they are checked.
+    pub(crate) async fn start_login(
+        &mut self,
+        name: &str,
+        address: IpAddr,
+    ) -> Result<Option<User>, Error> {
+        self.check_lockout(name, address)?;
+        self.find_user(name).await
+    }
+
+    fn check_lockout(&self, name: &str, address: IpAddr) -> Result<(), Error> {
+        let policy = &self.lockout_policy;
+        let now = Instant::now();
+        let user = self
+            .lockout
+            .users
+            .get(name)
+            .filter(|failures| !failures.is_forgotten(now, policy))
+            .and_then(|failures| failures.locked_until(policy.max_user_failures, policy));
+        let address = self
+            .lockout
+            .addresses
+            .get(&address_key(address))
+            .filter(|failures| !failures.is_forgotten(now, policy))
+            .and_then(|failures| failures.locked_until(policy.max_address_failures, policy));
 
-        #[allow(clippy::redundant_closure)]
-        let saved_password =
-            PasswordHash::new(&user.password).map_err(|e| Into::<AuthError>::into(e))?;
+        match user.max(address) {
+            Some(locked_until) if locked_until > now => {
+                Err(AuthError::LockedOut(locked_until - now).into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Counts the failed logins. Unknown users count as well, otherwise a lockout would reveal that a user exists.
+    pub(crate) fn record_login(
+        &mut self,
+        name: &str,
+        address: IpAddr,
+        result: &Result<User, Error>,
+    ) {
+        match result {
+            Ok(_) => {
+                self.lockout.users.remove(name);
+            }
+            Err(Error::AuthenticationError(
+                AuthError::UserNotFound | AuthError::WrongPassword | AuthError::WrongKey,
+            )) => {
+                let now = Instant::now();
+                let policy = &self.lockout_policy;
+                let lockout = &mut self.lockout;
+                for failures in [
+                    lockout.users.entry(name.to_string()).or_insert(Failures {
+                        count: 0,
+                        last_failure: now,
+                    }),
+                    lockout
+                        .addresses
+                        .entry(address_key(address))
+                        .or_insert(Failures {
+                            count: 0,
+                            last_failure: now,
+                        }),
+                ] {
+                    failures.record(now, policy);
+                }
 
-        match Argon2::default().verify_password(password.as_bytes(), &saved_password) {
-            Ok(()) => Ok(user),
-            Err(password_hash::errors::Error::Password) => Err(AuthError::WrongPassword.into()),
-            Err(e) => {
-                let e: AuthError = e.into();
-                Err(e.into())
+                // forget the old failures, so the maps don't keep growing.
+                // Only done once they grew, so a failure doesn't have to go through all of them
+                if lockout.users.len() + lockout.addresses.len() >= lockout.prune_at {
+                    lockout
+                        .users
+                        .retain(|_, failures| !failures.is_forgotten(now, policy));
+                    lockout
+                        .addresses
+                        .retain(|_, failures| !failures.is_forgotten(now, policy));
+                    lockout.prune_at =
+                        MIN_PRUNE_AT.max(2 * (lockout.users.len() + lockout.addresses.len()));
+                }
             }
+            Err(_) => (),
+        }
+    }
+
+    /// Returns the user the client certificate is mapped to. The certificate has to be verified already
+    pub async fn get_user_by_certificate(
+        &mut self,
+        certificate: &Certificate,
+    ) -> Result<User, Error> {
+        self.storage
+            .get_user_by_certificate(&certificate_fingerprint(certificate))
+            .await
+    }
+
+    /// Returns the user if `password` is its password.
+    ///
+    /// If the user doesn't exist, `password` is verified against a dummy hash before failing with
+    /// [AuthError::UserNotFound], so the time taken doesn't reveal whether it does.
+    pub async fn get_user<'a>(&mut self, name: &'a str, password: &'a str) -> Result<User, Error> {
+        let user = self.find_user(name).await?;
+        check_password(user, password).await
+    }
+
+    /// Returns the user `name`, or `None` if it doesn't exist
+    async fn find_user(&mut self, name: &str) -> Result<Option<User>, Error> {
+        match self.storage.get_user(name).await {
+            Ok(user) => Ok(Some(user)),
+            Err(Error::AuthenticationError(AuthError::UserNotFound)) => Ok(None),
+            Err(e) => Err(e),
         }
     }
 }
 
-#[derive(Serialize, Deserialize)]
+/// Returns `user` if `password` is its password, fails with [AuthError::UserNotFound] if it's `None`.
+///
+/// The password of a user that doesn't exist is verified against a dummy hash, so the time taken doesn't reveal
+/// whether it does. The hash is verified on a blocking thread, since it takes long enough to hold up the runtime.
+pub(crate) async fn check_password(user: Option<User>, password: &str) -> Result<User, Error> {
+    let password = password.to_string();
+    tokio::task::spawn_blocking(move || {
+        let verified = verify_password(&password, password_hash(user.as_ref()))?;
+
+        match user {
+            Some(user) if verified => Ok(user),
+            Some(_) => Err(AuthError::WrongPassword.into()),
+            None => Err(AuthError::UserNotFound.into()),
+        }
+    })
+    .await
+    .map_err(std::io::Error::other)?
+}
+
+/// Returns `user` if `public_key` is one of its authorized keys and `signature` is its signature of `data`,
+/// fails with [AuthError::UserNotFound] if it's `None`.
+///
+/// The signature is verified even if the user doesn't exist, so the time taken doesn't reveal whether it does.
+pub(crate) fn check_public_key(
+    user: Option<User>,
+    public_key: &[u8],
+    data: &[u8],
+    signature: &[u8],
+) -> Result<User, Error> {
+    let verified = signature::UnparsedPublicKey::new(&signature::ED25519, public_key)
+        .verify(data, signature)
+        .is_ok();
+
+    match user {
+        Some(user) if verified && user.authorized_keys.contains(&hex(public_key)) => Ok(user),
+        Some(_) => Err(AuthError::WrongKey.into()),
+        None => Err(AuthError::UserNotFound.into()),
+    }
+}
+
+/// The hash a password is verified against, [DUMMY_PASSWORD_HASH] if the user doesn't exist
+fn password_hash(user: Option<&User>) -> &str {
+    user.map_or(DUMMY_PASSWORD_HASH, |user| &user.password)
+}
+
+fn verify_password(password: &str, hash: &str) -> Result<bool, Error> {
+    #[allow(clippy::redundant_closure)]
+    let hash = PasswordHash::new(hash).map_err(|e| Into::<AuthError>::into(e))?;
+
+    match Argon2::default().verify_password(password.as_bytes(), &hash) {
+        Ok(()) => Ok(true),
+        Err(password_hash::errors::Error::Password) => Ok(false),
+        Err(e) => {
+            let e: AuthError = e.into();
+            Err(e.into())
+        }
+    }
+}
+
+fn hash_password(password: &str) -> Result<String, Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    let password_hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(AuthError::from)?;
+    Ok(password_hash.to_string())
+}
+
+/// The lowercase hex encoded SHA-256 hash of the DER encoded certificate
+pub fn certificate_fingerprint(certificate: &Certificate) -> String {
+    hex(digest::digest(&digest::SHA256, &certificate.0).as_ref())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct User {
     name: String,
     password: String,
@@ -149,6 +801,42 @@ pub struct User {
     // currently the whole auth system only works on unix
     uid: u32,
     gid: Vec<u32>,
+    /// The directory all operations of the user are scoped to. A relative path is a subdirectory of the base path
+    /// of the server, an absolute path is used as is. `None` gives access to the whole base path.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    root: Option<PathBuf>,
+    /// The [fingerprints](certificate_fingerprint) of the client certificates the user can log in with
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    certificates: Vec<String>,
+    /// The hex encoded Ed25519 public keys the user can log in with
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    authorized_keys: Vec<String>,
+}
+
+impl User {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    pub fn gid(&self) -> &[u32] {
+        &self.gid
+    }
+
+    pub fn root(&self) -> Option<&Path> {
+        self.root.as_deref()
+    }
+
+    pub fn certificates(&self) -> &[String] {
+        &self.certificates
+    }
+
+    pub fn authorized_keys(&self) -> &[String] {
+        &self.authorized_keys
+    }
 }
 
 // Implement Debug manually since we don't want the password to be logged
@@ -159,6 +847,9 @@ impl fmt::Debug for User {
             .field("password", &"***")
             .field("uid", &self.uid)
             .field("gid", &self.gid)
+            .field("root", &self.root)
+            .field("certificates", &self.certificates)
+            .field("authorized_keys", &self.authorized_keys)
             .finish()
     }
 }
@@ -167,47 +858,436 @@ impl fmt::Debug for User {
 mod test {
     use super::*;
     use crate::Error;
+    use std::os::unix::fs::PermissionsExt;
     #[tokio::test]
     async fn test_add_user() {
-        let storage = FileStorage::new("./users.json")
-            .await
-            .expect("couldn't create FileStorage");
-        let mut manager = AuthManager::new(storage);
+        let mut manager = AuthManager::new(InMemoryStorage::new());
         let result = manager
             .add_user(
                 "test_user".to_string(),
                 "test".to_string(),
                 1001,
                 vec![1001, 23, 523],
+                None,
             )
             .await;
         assert!(result.is_ok());
-        tokio::fs::remove_file("./users.json").await.unwrap();
+        assert!(manager.get_user("test_user", "test").await.is_ok());
     }
 
     #[tokio::test]
     async fn test_auth_user() {
-        let storage = FileStorage::new("./test_auth_user.json")
-            .await
-            .expect("couldn't create FileStorage");
-        let mut manager = AuthManager::new(storage);
+        let mut manager = AuthManager::new(InMemoryStorage::new());
         manager
             .add_user(
                 "test_user".to_string(),
                 "test".to_string(),
                 1000,
                 vec![1000],
+                Some(PathBuf::from("test_user")),
             )
             .await
             .unwrap();
-        let user = manager.get_user("test_user", "test").await;
-        assert!(user.is_ok());
+        let user = manager.get_user("test_user", "test").await.unwrap();
+        assert_eq!(user.root(), Some(Path::new("test_user")));
         let user = manager.get_user("test_user", "wrong_pass").await;
         assert!(
             matches!(user, Err(e) if matches!(e, Error::AuthenticationError(AuthError::WrongPassword)))
         );
-        tokio::fs::remove_file("./test_auth_user.json")
+    }
+
+    #[tokio::test]
+    async fn test_unknown_user_verifies_password() {
+        // the dummy hash takes as much work to verify as the hash of a real password
+        let dummy = PasswordHash::new(DUMMY_PASSWORD_HASH).unwrap();
+        let hash = hash_password("test").unwrap();
+        let hash = PasswordHash::new(&hash).unwrap();
+        assert_eq!(dummy.algorithm, hash.algorithm);
+        assert_eq!(dummy.version, hash.version);
+        assert_eq!(dummy.params, hash.params);
+        assert!(!verify_password("test", DUMMY_PASSWORD_HASH).unwrap());
+
+        let mut manager = AuthManager::new(InMemoryStorage::new());
+        manager
+            .add_user(
+                "test_user".to_string(),
+                "test".to_string(),
+                1000,
+                vec![1000],
+                None,
+            )
+            .await
+            .unwrap();
+        let user = manager.storage.get_user("test_user").await.unwrap();
+        assert_eq!(password_hash(Some(&user)), user.password);
+        assert_eq!(password_hash(None), DUMMY_PASSWORD_HASH);
+
+        // both failures verify a password, they only differ in the error
+        let result = manager.get_user("unknown", "test").await;
+        assert!(matches!(
+            result,
+            Err(Error::AuthenticationError(AuthError::UserNotFound))
+        ));
+        let result = manager.get_user("test_user", "wrong_pass").await;
+        assert!(matches!(
+            result,
+            Err(Error::AuthenticationError(AuthError::WrongPassword))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_lockout() {
+        let policy = LockoutPolicy {
+            max_user_failures: 2,
+            max_address_failures: 3,
+            lockout: Duration::from_millis(200),
+            max_lockout: Duration::from_secs(60),
+            reset_after: Duration::from_secs(60),
+        };
+        let mut manager = AuthManager::new(InMemoryStorage::new()).with_lockout_policy(policy);
+        manager
+            .add_user(
+                "test_user".to_string(),
+                "test".to_string(),
+                1000,
+                vec![1000],
+                None,
+            )
+            .await
+            .unwrap();
+        let address: IpAddr = "10.0.0.1".parse().unwrap();
+        let other_address: IpAddr = "10.0.0.2".parse().unwrap();
+        let is_locked_out = |result: Result<User, Error>| {
+            matches!(
+                result,
+                Err(Error::AuthenticationError(AuthError::LockedOut(_)))
+            )
+        };
+
+        for _ in 0..2 {
+            assert!(manager
+                .login_password("test_user", "wrong_pass", address)
+                .await
+                .is_err());
+        }
+        // even the right password is rejected while the user is locked out
+        let result = manager
+            .login_password("test_user", "test", other_address)
+            .await;
+        assert!(is_locked_out(result));
+
+        // unknown users are counted too, locking out the address
+        let result = manager.login_password("unknown", "test", address).await;
+        assert!(matches!(
+            result,
+            Err(Error::AuthenticationError(AuthError::UserNotFound))
+        ));
+        let result = manager.login_password("other", "test", address).await;
+        assert!(is_locked_out(result));
+
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        manager
+            .login_password("test_user", "test", other_address)
             .await
             .unwrap();
+        // the address failures aren't reset by a successful login, so the next one doubles its lockout
+        let result = manager
+            .login_password("test_user", "wrong_pass", address)
+            .await;
+        assert!(result.is_err());
+        let result = manager.login_password("test_user", "test", address).await;
+        assert!(matches!(
+            result,
+            Err(Error::AuthenticationError(AuthError::LockedOut(lockout)))
+                if lockout > Duration::from_millis(200)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_lockout_ipv6_network() {
+        let key = |address: &str| address_key(address.parse().unwrap());
+        assert_eq!(key("10.0.0.1"), key("10.0.0.1"));
+        assert_ne!(key("10.0.0.1"), key("10.0.0.2"));
+        assert_eq!(key("::ffff:10.0.0.1"), key("10.0.0.1"));
+        assert_eq!(key("2001:db8:1:2:aaaa::1"), key("2001:db8:1:2:bbbb::2"));
+        assert_ne!(key("2001:db8:1:2::1"), key("2001:db8:1:3::1"));
+
+        let policy = LockoutPolicy {
+            max_user_failures: 100,
+            max_address_failures: 3,
+            lockout: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let mut manager = AuthManager::new(InMemoryStorage::new()).with_lockout_policy(policy);
+        // a client rotating through its /64 is still locked out
+        for i in 1..=3 {
+            let address = format!("2001:db8:1:2::{i}").parse().unwrap();
+            let result = manager.login_password("unknown", "test", address).await;
+            assert!(matches!(
+                result,
+                Err(Error::AuthenticationError(AuthError::UserNotFound))
+            ));
+        }
+        let address = "2001:db8:1:2::4".parse().unwrap();
+        let result = manager.login_password("unknown", "test", address).await;
+        assert!(matches!(
+            result,
+            Err(Error::AuthenticationError(AuthError::LockedOut(_)))
+        ));
+        let address = "2001:db8:1:3::1".parse().unwrap();
+        let result = manager.login_password("unknown", "test", address).await;
+        assert!(matches!(
+            result,
+            Err(Error::AuthenticationError(AuthError::UserNotFound))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_certificate_user() {
+        let mut manager = AuthManager::new(InMemoryStorage::new());
+        manager
+            .add_user(
+                "test_user".to_string(),
+                "test".to_string(),
+                1000,
+                vec![1000],
+                None,
+            )
+            .await
+            .unwrap();
+        let certificate = Certificate(b"test certificate".to_vec());
+        let fingerprint = certificate_fingerprint(&certificate);
+        assert_eq!(fingerprint.len(), 64);
+
+        // the openssl notation is accepted as well
+        let openssl_fingerprint = fingerprint
+            .as_bytes()
+            .chunks(2)
+            .map(|b| std::str::from_utf8(b).unwrap().to_uppercase())
+            .collect::<Vec<_>>()
+            .join(":");
+        manager
+            .set_certificates("test_user", vec![openssl_fingerprint])
+            .await
+            .unwrap();
+        let user = manager.get_user_by_certificate(&certificate).await.unwrap();
+        assert_eq!(user.name(), "test_user");
+        assert_eq!(user.certificates(), [fingerprint]);
+        assert!(matches!(
+            manager
+                .get_user_by_certificate(&Certificate(b"other certificate".to_vec()))
+                .await,
+            Err(Error::AuthenticationError(AuthError::UserNotFound))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_public_key_user() {
+        use ring::{rand::SystemRandom, signature::Ed25519KeyPair, signature::KeyPair};
+
+        let mut manager = AuthManager::new(InMemoryStorage::new());
+        manager
+            .add_user(
+                "test_user".to_string(),
+                "test".to_string(),
+                1000,
+                vec![1000],
+                None,
+            )
+            .await
+            .unwrap();
+        fn key_pair() -> Ed25519KeyPair {
+            let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+            Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap()
+        }
+        let (key, other_key) = (key_pair(), key_pair());
+        let public_key = key.public_key().as_ref();
+        assert!(matches!(
+            manager
+                .set_authorized_keys("test_user", vec!["not a key".to_string()])
+                .await,
+            Err(Error::AuthenticationError(AuthError::InvalidKey(_)))
+        ));
+        manager
+            .set_authorized_keys("test_user", vec![hex(public_key).to_uppercase()])
+            .await
+            .unwrap();
+
+        let signature = key.sign(b"challenge");
+        let user = manager
+            .get_user_by_public_key("test_user", public_key, b"challenge", signature.as_ref())
+            .await
+            .unwrap();
+        assert_eq!(user.authorized_keys(), [hex(public_key)]);
+        // the signature has to match the data and the key has to be authorized
+        assert!(matches!(
+            manager
+                .get_user_by_public_key("test_user", public_key, b"other", signature.as_ref())
+                .await,
+            Err(Error::AuthenticationError(AuthError::WrongKey))
+        ));
+        let signature = other_key.sign(b"challenge");
+        assert!(matches!(
+            manager
+                .get_user_by_public_key(
+                    "test_user",
+                    other_key.public_key().as_ref(),
+                    b"challenge",
+                    signature.as_ref()
+                )
+                .await,
+            Err(Error::AuthenticationError(AuthError::WrongKey))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_file_storage() {
+        let path = std::env::temp_dir().join(format!(
+            "qftp_test_file_storage_{}.json",
+            std::process::id()
+        ));
+        let mut manager = AuthManager::new(FileStorage::new(&path).await.unwrap());
+        manager
+            .add_user(
+                "test_user".to_string(),
+                "test".to_string(),
+                1000,
+                vec![1000],
+                None,
+            )
+            .await
+            .unwrap();
+        let mut manager = AuthManager::new(FileStorage::new(&path).await.unwrap());
+        let user = manager.get_user("test_user", "test").await.unwrap();
+        assert_eq!(user.uid(), 1000);
+
+        // removing a user writes a shorter document than before
+        manager
+            .add_user(
+                "other_user".to_string(),
+                "other".to_string(),
+                1001,
+                vec![1001],
+                None,
+            )
+            .await
+            .unwrap();
+        manager.remove_user("other_user").await.unwrap();
+        let mut manager = AuthManager::new(FileStorage::new(&path).await.unwrap());
+        assert!(manager.get_user("test_user", "test").await.is_ok());
+
+        // replacing the file keeps its permissions
+        tokio::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))
+            .await
+            .unwrap();
+        manager
+            .add_user(
+                "third_user".to_string(),
+                "third".to_string(),
+                1002,
+                vec![1002],
+                None,
+            )
+            .await
+            .unwrap();
+        let metadata = tokio::fs::metadata(&path).await.unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        tokio::fs::remove_file(&path).await.unwrap();
+        tokio::fs::remove_file(manager.storage.with_suffix(".lock"))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_file_storage_concurrent_writers() {
+        let path = std::env::temp_dir().join(format!(
+            "qftp_test_file_storage_concurrent_{}.json",
+            std::process::id()
+        ));
+        let writers = (0..4).map(|writer| {
+            let path = path.clone();
+            tokio::spawn(async move {
+                let mut storage = FileStorage::new(&path).await.unwrap();
+                for i in 0..10 {
+                    let user = User {
+                        name: format!("user_{writer}_{i}"),
+                        password: String::new(),
+                        uid: 1000,
+                        gid: vec![1000],
+                        root: None,
+                        certificates: Vec::new(),
+                        authorized_keys: Vec::new(),
+                    };
+                    storage.add_user(user).await.unwrap();
+                }
+            })
+        });
+        for writer in futures::future::join_all(writers).await {
+            writer.unwrap();
+        }
+
+        let mut storage = FileStorage::new(&path).await.unwrap();
+        assert_eq!(storage.get_users().await.unwrap().len(), 40);
+        assert!(!storage.with_suffix(".tmp").exists());
+        tokio::fs::remove_file(&path).await.unwrap();
+        tokio::fs::remove_file(storage.with_suffix(".lock"))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_user_management() {
+        let mut manager = AuthManager::new(InMemoryStorage::new());
+        manager
+            .add_user(
+                "test_user".to_string(),
+                "test".to_string(),
+                1000,
+                vec![1000],
+                None,
+            )
+            .await
+            .unwrap();
+        let result = manager
+            .add_user(
+                "test_user".to_string(),
+                "other".to_string(),
+                1001,
+                vec![1001],
+                None,
+            )
+            .await;
+        assert!(matches!(
+            result,
+            Err(Error::AuthenticationError(AuthError::UserExists))
+        ));
+
+        manager.set_password("test_user", "new").await.unwrap();
+        assert!(manager.get_user("test_user", "test").await.is_err());
+        manager
+            .update_user(
+                "test_user",
+                1002,
+                vec![1002, 20],
+                Some(PathBuf::from("home")),
+            )
+            .await
+            .unwrap();
+        let user = manager.get_user("test_user", "new").await.unwrap();
+        assert_eq!(user.uid(), 1002);
+        assert_eq!(user.gid(), [1002, 20]);
+        assert_eq!(user.root(), Some(Path::new("home")));
+
+        manager.remove_user("test_user").await.unwrap();
+        assert!(matches!(
+            manager.get_user("test_user", "new").await,
+            Err(Error::AuthenticationError(AuthError::UserNotFound))
+        ));
+        assert!(matches!(
+            manager
+                .update_user("test_user", 1000, vec![1000], None)
+                .await,
+            Err(Error::AuthenticationError(AuthError::UserNotFound))
+        ));
     }
 }
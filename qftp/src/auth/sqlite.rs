@@ -0,0 +1,386 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use rusqlite::{params, Connection, ErrorCode, Row};
+
+use super::{AuthError, Storage, User};
+use crate::Error;
+
+const SCHEMA: &str = "
+    PRAGMA foreign_keys = ON;
+    CREATE TABLE IF NOT EXISTS users (
+        name TEXT PRIMARY KEY NOT NULL,
+        password TEXT NOT NULL,
+        uid INTEGER NOT NULL,
+        gid TEXT NOT NULL,
+        root TEXT
+    );
+    CREATE TABLE IF NOT EXISTS certificates (
+        fingerprint TEXT PRIMARY KEY NOT NULL,
+        name TEXT NOT NULL REFERENCES users (name) ON UPDATE CASCADE ON DELETE CASCADE
+    );
+    CREATE TABLE IF NOT EXISTS authorized_keys (
+        name TEXT NOT NULL REFERENCES users (name) ON UPDATE CASCADE ON DELETE CASCADE,
+        public_key TEXT NOT NULL,
+        PRIMARY KEY (name, public_key)
+    );";
+
+/// Stores the users in an SQLite database.
+///
+/// Unlike [FileStorage](super::FileStorage) a lookup doesn't read all users, the `name` column is the primary key
+/// of the `users` table and the certificate `fingerprint` the primary key of the `certificates` table.
+#[derive(Debug)]
+pub struct SqliteStorage {
+    // rusqlite is blocking, so every query runs on the blocking thread pool
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStorage {
+    /// Opens the database at `path` and creates the `users` table if it doesn't exist yet
+    pub async fn new(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref().to_owned();
+        let connection = tokio::task::spawn_blocking(move || {
+            let connection = Connection::open(path)?;
+            connection.execute_batch(SCHEMA)?;
+            Ok::<_, Error>(connection)
+        })
+        .await
+        .map_err(std::io::Error::other)??;
+
+        Ok(SqliteStorage {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Imports all users of an `auth.json` written by [FileStorage](super::FileStorage).
+    /// Either all users are imported or none. Returns the number of imported users.
+    pub async fn import_json(&mut self, auth_file: impl AsRef<Path>) -> Result<usize, Error> {
+        let json = tokio::fs::read_to_string(auth_file).await?;
+        let users: Vec<User> = match json.is_empty() {
+            true => Vec::new(),
+            false => serde_json::from_str(&json)?,
+        };
+
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction()?;
+            for user in &users {
+                insert_user(&transaction, user)?;
+            }
+            transaction.commit()?;
+            Ok(users.len())
+        })
+        .await
+    }
+
+    async fn with_connection<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, Error> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut connection = connection.lock().expect("sqlite connection poisoned");
+            f(&mut connection)
+        })
+        .await
+        .map_err(std::io::Error::other)?
+    }
+}
+
+/// Has to run in a transaction, the user and its certificates and keys are inserted separately
+fn insert_user(connection: &Connection, user: &User) -> Result<(), Error> {
+    let root = root_str(user)?;
+    let result = connection.execute(
+        "INSERT INTO users (name, password, uid, gid, root) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            user.name,
+            user.password,
+            user.uid,
+            serde_json::to_string(&user.gid)?,
+            root
+        ],
+    );
+    match result {
+        Ok(_) => (),
+        // the name is the primary key, so this is the only constraint that can be violated
+        Err(rusqlite::Error::SqliteFailure(e, _)) if e.code == ErrorCode::ConstraintViolation => {
+            return Err(AuthError::UserExists.into());
+        }
+        Err(e) => return Err(e.into()),
+    }
+    insert_keys(connection, user)
+}
+
+/// Inserts the certificates and authorized keys of the user
+fn insert_keys(connection: &Connection, user: &User) -> Result<(), Error> {
+    let mut statement =
+        connection.prepare("INSERT INTO certificates (fingerprint, name) VALUES (?1, ?2)")?;
+    for fingerprint in &user.certificates {
+        statement.execute([fingerprint, &user.name])?;
+    }
+    let mut statement =
+        connection.prepare("INSERT INTO authorized_keys (name, public_key) VALUES (?1, ?2)")?;
+    for public_key in &user.authorized_keys {
+        statement.execute([&user.name, public_key])?;
+    }
+    Ok(())
+}
+
+fn read_column(connection: &Connection, query: &str, name: &str) -> Result<Vec<String>, Error> {
+    let mut statement = connection.prepare(query)?;
+    let values = statement
+        .query_map([name], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(values)
+}
+
+/// Reads the user in `row` together with its certificates and keys
+fn read_user(connection: &Connection, row: &Row) -> Result<User, Error> {
+    let name: String = row.get(0)?;
+    let gid: String = row.get(3)?;
+    let certificates = read_column(
+        connection,
+        "SELECT fingerprint FROM certificates WHERE name = ?1",
+        &name,
+    )?;
+    let authorized_keys = read_column(
+        connection,
+        "SELECT public_key FROM authorized_keys WHERE name = ?1",
+        &name,
+    )?;
+
+    Ok(User {
+        name,
+        password: row.get(1)?,
+        uid: row.get(2)?,
+        // the gids are stored as a JSON array
+        gid: serde_json::from_str(&gid)?,
+        root: row.get::<_, Option<String>>(4)?.map(PathBuf::from),
+        certificates,
+        authorized_keys,
+    })
+}
+
+/// Runs `query` for a single user, fails with [AuthError::UserNotFound] if there is none
+fn query_user(connection: &Connection, query: &str, param: String) -> Result<User, Error> {
+    let mut statement = connection.prepare(query)?;
+    let mut rows = statement.query([param])?;
+    match rows.next()? {
+        Some(row) => read_user(connection, row),
+        None => Err(AuthError::UserNotFound.into()),
+    }
+}
+
+fn root_str(user: &User) -> Result<Option<&str>, Error> {
+    match &user.root {
+        Some(root) => Ok(Some(root.to_str().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("the root {root:?} of `{}` isn't valid UTF-8", user.name),
+            )
+        })?)),
+        None => Ok(None),
+    }
+}
+
+/// Fails with [AuthError::UserNotFound] if the statement didn't change any row
+fn changed_user(changed: usize) -> Result<(), Error> {
+    match changed {
+        0 => Err(AuthError::UserNotFound.into()),
+        _ => Ok(()),
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for SqliteStorage {
+    async fn add_user(&mut self, user: User) -> Result<(), Error> {
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction()?;
+            insert_user(&transaction, &user)?;
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_user<'a>(&mut self, name: &'a str) -> Result<User, Error> {
+        let name = name.to_owned();
+        self.with_connection(move |connection| {
+            query_user(
+                connection,
+                "SELECT name, password, uid, gid, root FROM users WHERE name = ?1",
+                name,
+            )
+        })
+        .await
+    }
+
+    async fn get_users(&mut self) -> Result<Vec<User>, Error> {
+        self.with_connection(|connection| {
+            let mut statement =
+                connection.prepare("SELECT name, password, uid, gid, root FROM users")?;
+            let mut rows = statement.query([])?;
+            let mut users = Vec::new();
+            while let Some(row) = rows.next()? {
+                users.push(read_user(connection, row)?);
+            }
+            Ok(users)
+        })
+        .await
+    }
+
+    async fn get_user_by_certificate<'a>(&mut self, fingerprint: &'a str) -> Result<User, Error> {
+        let fingerprint = fingerprint.to_owned();
+        self.with_connection(move |connection| {
+            query_user(
+                connection,
+                "SELECT users.name, password, uid, gid, root FROM users
+                    JOIN certificates ON users.name = certificates.name WHERE fingerprint = ?1",
+                fingerprint,
+            )
+        })
+        .await
+    }
+
+    async fn remove_user<'a>(&mut self, name: &'a str) -> Result<(), Error> {
+        let name = name.to_owned();
+        self.with_connection(move |connection| {
+            changed_user(connection.execute("DELETE FROM users WHERE name = ?1", [name])?)
+        })
+        .await
+    }
+
+    async fn update_user(&mut self, user: User) -> Result<(), Error> {
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction()?;
+            let changed = transaction.execute(
+                "UPDATE users SET password = ?2, uid = ?3, gid = ?4, root = ?5 WHERE name = ?1",
+                params![
+                    user.name,
+                    user.password,
+                    user.uid,
+                    serde_json::to_string(&user.gid)?,
+                    root_str(&user)?
+                ],
+            )?;
+            changed_user(changed)?;
+            transaction.execute("DELETE FROM certificates WHERE name = ?1", [&user.name])?;
+            transaction.execute("DELETE FROM authorized_keys WHERE name = ?1", [&user.name])?;
+            insert_keys(&transaction, &user)?;
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn set_password<'a>(
+        &mut self,
+        name: &'a str,
+        password_hash: String,
+    ) -> Result<(), Error> {
+        let name = name.to_owned();
+        self.with_connection(move |connection| {
+            changed_user(connection.execute(
+                "UPDATE users SET password = ?2 WHERE name = ?1",
+                [name, password_hash],
+            )?)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::auth::{certificate_fingerprint, AuthManager, FileStorage};
+    use rustls::Certificate;
+
+    #[tokio::test]
+    async fn test_sqlite_storage() {
+        let dir = std::env::temp_dir().join(format!("qftp_test_sqlite_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let auth_file = dir.join("auth.json");
+        let mut manager = AuthManager::new(FileStorage::new(&auth_file).await.unwrap());
+        manager
+            .add_user(
+                "team_a".to_string(),
+                "a".to_string(),
+                1000,
+                vec![1000, 20],
+                Some(PathBuf::from("a")),
+            )
+            .await
+            .unwrap();
+        manager
+            .add_user(
+                "team_b".to_string(),
+                "b".to_string(),
+                1001,
+                vec![1001],
+                None,
+            )
+            .await
+            .unwrap();
+
+        let mut storage = SqliteStorage::new(dir.join("users.db")).await.unwrap();
+        assert_eq!(storage.import_json(&auth_file).await.unwrap(), 2);
+        // importing the same users again fails and doesn't import anything
+        assert!(matches!(
+            storage.import_json(&auth_file).await,
+            Err(Error::AuthenticationError(AuthError::UserExists))
+        ));
+        assert_eq!(storage.get_users().await.unwrap().len(), 2);
+
+        // the database survives reopening it
+        let storage = SqliteStorage::new(dir.join("users.db")).await.unwrap();
+        let mut manager = AuthManager::new(storage);
+        let user = manager.get_user("team_a", "a").await.unwrap();
+        assert_eq!(user.uid(), 1000);
+        assert_eq!(user.gid(), [1000, 20]);
+        assert_eq!(user.root(), Some(Path::new("a")));
+        assert!(matches!(
+            manager.get_user("team_c", "c").await,
+            Err(Error::AuthenticationError(AuthError::UserNotFound))
+        ));
+
+        manager.set_password("team_a", "new").await.unwrap();
+        assert!(manager.get_user("team_a", "new").await.is_ok());
+        manager
+            .update_user("team_a", 1002, vec![1002], None)
+            .await
+            .unwrap();
+        let user = manager.get_user("team_a", "new").await.unwrap();
+        assert_eq!((user.uid(), user.root()), (1002, None));
+        manager.remove_user("team_b").await.unwrap();
+        assert!(matches!(
+            manager.remove_user("team_b").await,
+            Err(Error::AuthenticationError(AuthError::UserNotFound))
+        ));
+
+        let certificate = Certificate(b"team_a certificate".to_vec());
+        manager
+            .set_certificates("team_a", vec![certificate_fingerprint(&certificate)])
+            .await
+            .unwrap();
+        let user = manager.get_user_by_certificate(&certificate).await.unwrap();
+        assert_eq!(user.name(), "team_a");
+        let public_key = "ab".repeat(32);
+        manager
+            .set_authorized_keys("team_a", vec![public_key.clone()])
+            .await
+            .unwrap();
+        let user = manager.get_user("team_a", "new").await.unwrap();
+        assert_eq!(user.authorized_keys(), [public_key]);
+        assert_eq!(user.certificates().len(), 1);
+        // removing the user removes its certificates as well
+        manager.remove_user("team_a").await.unwrap();
+        assert!(matches!(
+            manager.get_user_by_certificate(&certificate).await,
+            Err(Error::AuthenticationError(AuthError::UserNotFound))
+        ));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
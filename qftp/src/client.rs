@@ -1,28 +1,42 @@
 use crate::{
+    credentials::{CredentialProvider, Credentials, KeyCredentials},
     distributor::{self, StreamRequest},
-    message::{self, Message},
-    Error,
+    files::{self, FileManager},
+    message::{self, LoginMethod, Message},
+    transfer, CloseCode, Error,
 };
-use quinn::Endpoint;
+use quinn::{Connection, Endpoint, RecvStream, SendStream};
 use rustls::{
-    client::{ServerCertVerified, ServerCertVerifier},
-    Certificate, ClientConfig, KeyLogFile, RootCertStore,
+    client::{ResolvesClientCert, ServerCertVerified, ServerCertVerifier},
+    sign::{self, CertifiedKey},
+    Certificate, ClientConfig, KeyLogFile, PrivateKey, RootCertStore, SignatureScheme,
 };
 
 use tokio::{
     io::AsyncWriteExt,
     sync::{
         mpsc::{self, UnboundedSender},
-        oneshot,
+        oneshot, Mutex,
     },
 };
 
 use crate::ControlStream;
 use std::{
+    collections::HashMap,
+    future::Future,
     net::{SocketAddr, ToSocketAddrs},
-    sync::Arc,
+    os::unix::fs::MetadataExt,
+    path::Path,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
 };
-use tracing::{debug, trace};
+use tracing::{debug, trace, warn};
+
+const CLIENT_SUPPORTED_VERSIONS: [u8; 1] = [1];
+
+type Responses = Arc<std::sync::Mutex<HashMap<u32, oneshot::Sender<message::Response>>>>;
 
 /// A simple wrapper around [Rustls ClientConfig](rustls::ClientConfig)
 #[derive(Debug)]
@@ -85,6 +99,24 @@ impl QClientConfig {
             client_config: config,
         }
     }
+
+    /// Presents `certs` to servers asking for a client certificate, see
+    /// [ServerBuilder::with_client_auth](crate::ServerBuilder::with_client_auth).
+    /// The first certificate is the one of the client, the rest is the chain up to the CA.
+    /// Like the server certificates, they and the private key have to be DER encoded.
+    pub fn with_client_cert(
+        mut self,
+        certs: Vec<Certificate>,
+        private_key: PrivateKey,
+    ) -> Result<Self, Error> {
+        let key = sign::any_supported_type(&private_key).map_err(|_| {
+            Error::CredentialError("the client private key is invalid or unsupported".to_string())
+        })?;
+        self.client_config.client_auth_cert_resolver =
+            Arc::new(ClientCert(Arc::new(CertifiedKey::new(certs, key))));
+
+        Ok(self)
+    }
 }
 
 impl From<QClientConfig> for ClientConfig {
@@ -93,12 +125,41 @@ impl From<QClientConfig> for ClientConfig {
     }
 }
 
+/// Cancels a request on the server if it's dropped before the request completed
+#[derive(Debug)]
+struct CancelOnDrop {
+    client: Client,
+    request_id: u32,
+    completed: bool,
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if self.completed {
+            return;
+        }
+
+        // the response to the cancelled request is still received, but there is no one waiting for it anymore
+        let client = self.client.clone();
+        let request_id = self.request_id;
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                if let Err(e) = client.cancel(request_id).await {
+                    debug!("failed to cancel request {request_id}: {e}");
+                }
+            });
+        }
+    }
+}
+
 /// Builder for a [Client]. Usually created by [Client::builder]
 #[derive(Debug)]
 pub struct ClientBuilder {
     addr: Option<SocketAddr>,
     server_name: Option<String>,
     config: Option<ClientConfig>,
+    credentials: Option<Arc<dyn CredentialProvider>>,
+    key: Option<KeyCredentials>,
 }
 
 impl ClientBuilder {
@@ -124,6 +185,29 @@ impl ClientBuilder {
         self
     }
 
+    /// Log in with a fixed name and password.
+    ///
+    /// The credentials aren't needed if the client presents a certificate, see
+    /// [QClientConfig::with_client_cert]
+    pub fn with_credentials(self, name: String, password: String) -> Self {
+        self.with_credential_provider(Credentials::new(name, password))
+    }
+
+    /// Log in with the [Credentials] returned by `provider`, see [credentials](crate::credentials) for the available providers
+    pub fn with_credential_provider(mut self, provider: impl CredentialProvider + 'static) -> Self {
+        self.credentials = Some(Arc::new(provider));
+
+        self
+    }
+
+    /// Log in with an Ed25519 key instead of a password. If [credentials](ClientBuilder::with_credentials) are set
+    /// as well, they are used if the server doesn't support logging in with a key.
+    pub fn with_key(mut self, key: KeyCredentials) -> Self {
+        self.key = Some(key);
+
+        self
+    }
+
     pub async fn build(self) -> Result<Client, Error> {
         Client::new(
             self.addr
@@ -132,15 +216,31 @@ impl ClientBuilder {
                 .expect("tried calling build without setting the server name"),
             self.config
                 .expect("tried calling build without setting the client_config"),
+            self.credentials,
+            self.key,
         )
         .await
     }
 }
 
 /// Entrypoint for creating a qftp Client
-#[derive(Debug)]
+///
+/// A [Client] is a handle to a single connection. Cloning it is cheap and all clones share the connection,
+/// so several requests can run on it at the same time.
+///
+/// Dropping the future of [list_files](Client::list_files), [get_files](Client::get_files) or
+/// [put_files](Client::put_files) before it completed cancels the request on the server,
+/// e.g. when it's used with [tokio::time::timeout].
+#[derive(Debug, Clone)]
 pub struct Client {
-    control_stream: ControlStream,
+    connection: Connection,
+    version: u8,
+    login_method: LoginMethod,
+    /// requests are written under the lock, so that concurrent requests don't interleave
+    control_stream: Arc<Mutex<SendStream>>,
+    /// senders for the requests waiting for their response on the control stream
+    responses: Responses,
+    next_request_id: Arc<AtomicU32>,
     recv_stream_request: UnboundedSender<StreamRequest>,
 }
 
@@ -150,13 +250,35 @@ impl Client {
             addr: None,
             server_name: None,
             config: None,
+            credentials: None,
+            key: None,
         }
     }
 
-    pub async fn shutdown(&mut self) -> Result<(), Error> {
+    /// The protocol version negotiated with the server
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// The method the client logged in with
+    pub fn login_method(&self) -> LoginMethod {
+        self.login_method
+    }
+
+    /// Finishes the control stream, which tells the server that no more requests will follow.
+    /// This affects every clone of the [Client].
+    pub async fn shutdown(&self) -> Result<(), Error> {
         debug!("shutting down the client");
         trace!("calling finish on the SendStream of the ControlStream");
-        self.control_stream.send().finish().await?;
+        match self.control_stream.lock().await.finish().await {
+            Ok(()) => (),
+            // the server already stopped reading the ControlStream or closed the connection cleanly
+            Err(quinn::WriteError::Stopped(code)) if code == quinn::VarInt::from_u32(0) => (),
+            Err(quinn::WriteError::ConnectionLost(quinn::ConnectionError::ApplicationClosed(
+                e,
+            ))) if e.error_code == quinn::VarInt::from_u32(0) => (),
+            Err(e) => return Err(e.into()),
+        };
         trace!("calling finish on the SendStream of the ControlStream returned");
         Ok(())
     }
@@ -175,7 +297,12 @@ impl Client {
         addr: SocketAddr,
         server_name: String,
         client_config: ClientConfig,
+        credentials: Option<Arc<dyn CredentialProvider>>,
+        key: Option<KeyCredentials>,
     ) -> Result<Self, Error> {
+        // whether the certificate was actually presented isn't known on a resumed session,
+        // so it's offered whenever there is one and the server decides
+        let certificate = client_config.client_auth_cert_resolver.has_certs();
         let client = Client::create_endpoint(client_config)?;
         debug!("Connecting to server");
         let connection = client.connect(addr, &server_name)?.await?;
@@ -184,73 +311,571 @@ impl Client {
         let control_stream = connection.open_bi().await?;
         let mut control_stream = ControlStream::new(control_stream.0, control_stream.1);
 
-        Client::negotiate_version(&mut control_stream).await?;
-        Client::login(&mut control_stream).await?;
+        let version = Client::negotiate_version(&connection, &mut control_stream).await?;
+        let login_method = Client::login(
+            &connection,
+            &mut control_stream,
+            &server_name,
+            certificate,
+            credentials,
+            key,
+        )
+        .await?;
+        let response = control_stream
+            .recv_message::<message::LoginResponse>()
+            .await?;
+        if !response.is_ok() {
+            return Err(Error::LoginError(response.reason().to_string()));
+        }
 
         let (tx, rx) = mpsc::unbounded_channel();
+        let (send, recv) = control_stream.into_parts();
 
         let client = Client {
-            control_stream,
+            connection: connection.clone(),
+            version,
+            login_method,
+            control_stream: Arc::new(Mutex::new(send)),
+            responses: Arc::default(),
+            next_request_id: Arc::new(AtomicU32::new(1)),
             recv_stream_request: tx,
         };
 
         tokio::spawn(distributor::run(connection, rx));
+        tokio::spawn(Client::recv_responses(recv, client.responses.clone()));
 
         Ok(client)
     }
 
-    async fn negotiate_version(control_stream: &mut ControlStream) -> Result<u8, Error> {
+    /// Reads the responses from the control stream and hands them to the request they belong to
+    async fn recv_responses(mut recv: RecvStream, responses: Responses) {
+        loop {
+            let response = match message::Response::next_response(&mut recv).await {
+                Ok(response) => response,
+                Err(e) => {
+                    debug!("stopped reading from the control stream: {e}");
+                    break;
+                }
+            };
+
+            let sender = responses.lock().unwrap().remove(&response.request_id());
+            match sender {
+                Some(sender) => {
+                    let _ = sender.send(response);
+                }
+                None => warn!("got a response for unknown request {response:?}"),
+            }
+        }
+
+        // dropping the senders wakes up all requests still waiting for a response
+        responses.lock().unwrap().clear();
+    }
+
+    /// Returns a request id that is unique on this connection
+    fn next_request_id(&self) -> u32 {
+        self.next_request_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Writes the request type followed by `request` to the control stream and returns the receiver for the response
+    /// of the server, which is sent once the request is done.
+    async fn send_request(
+        &self,
+        request_type: u16,
+        request_id: u32,
+        request: Vec<u8>,
+    ) -> Result<oneshot::Receiver<message::Response>, Error> {
+        // the sender has to be registered before sending the request, the response could arrive before it is otherwise
+        let (tx, rx) = oneshot::channel();
+        self.responses.lock().unwrap().insert(request_id, tx);
+
+        trace!("sending request {request_type:#04x} with id {request_id}");
+        if let Err(e) = self.write_request(request_type, request).await {
+            self.responses.lock().unwrap().remove(&request_id);
+            return Err(e);
+        }
+
+        Ok(rx)
+    }
+
+    /// Writes the request type followed by `request` to the control stream.
+    /// Everything is written at once, so requests sent concurrently by clones of the [Client] don't interleave.
+    /// The write runs in its own task, a dropped request can't leave a partially written request behind.
+    async fn write_request(&self, request_type: u16, request: Vec<u8>) -> Result<(), Error> {
+        let mut buf = Vec::with_capacity(2 + request.len());
+        buf.extend_from_slice(&request_type.to_be_bytes());
+        buf.extend_from_slice(&request);
+
+        let control_stream = self.control_stream.clone();
+        tokio::spawn(async move { control_stream.lock().await.write_all(&buf).await })
+            .await
+            .map_err(transfer::join_error)??;
+
+        Ok(())
+    }
+
+    /// Tells the server to stop the request `request_id`. The server answers the request itself once it stopped.
+    async fn cancel(&self, request_id: u32) -> Result<(), Error> {
+        trace!("cancelling request {request_id}");
+        let request = message::CancelRequest::new(request_id);
+        self.write_request(0x09, request.to_bytes()).await
+    }
+
+    /// Drives `request` to completion and waits for the response of the server.
+    /// If the server reports an error, it's returned instead of the result of `request`, as it's more precise.
+    ///
+    /// If the returned future is dropped before, the request is cancelled on the server.
+    async fn complete<T>(
+        &self,
+        request_id: u32,
+        response: oneshot::Receiver<message::Response>,
+        request: impl Future<Output = Result<T, Error>>,
+    ) -> Result<T, Error> {
+        let mut cancel_on_drop = CancelOnDrop {
+            client: self.clone(),
+            request_id,
+            completed: false,
+        };
+        let result = Client::complete_impl(response, request).await;
+        cancel_on_drop.completed = true;
+
+        result
+    }
+
+    async fn complete_impl<T>(
+        mut response: oneshot::Receiver<message::Response>,
+        request: impl Future<Output = Result<T, Error>>,
+    ) -> Result<T, Error> {
+        tokio::pin!(request);
+        tokio::select! {
+            result = &mut request => {
+                if let Ok(response) = response.await {
+                    Client::check_response(response)?;
+                }
+                result
+            }
+            response = &mut response => {
+                // the server might respond before all of its streams have been received
+                Client::check_response(response?)?;
+                request.await
+            }
+        }
+    }
+
+    fn check_response(response: message::Response) -> Result<(), Error> {
+        match response {
+            message::Response::StatusResponse(_) => Ok(()),
+            message::Response::ErrorResponse(response) => Err(response.into()),
+        }
+    }
+
+    /// Waits for the uni streams the server opens for `request_id`
+    async fn recv_streams(
+        &self,
+        request_id: u32,
+        num_streams: u16,
+    ) -> Result<Vec<RecvStream>, Error> {
+        let (tx, rx) = oneshot::channel();
+        let req = StreamRequest::new(num_streams, request_id, tx);
+        trace!("sending recv_stream_request");
+        self.recv_stream_request
+            .send(req)
+            .map_err(|_| Error::RequestDistributorChannelSendError)?;
+        let streams = rx.await?;
+        trace!("got all {} streams", streams.len());
+
+        Ok(streams)
+    }
+
+    async fn negotiate_version(
+        connection: &Connection,
+        control_stream: &mut ControlStream,
+    ) -> Result<u8, Error> {
         debug!("doing version negotation");
-        let version = message::Version::new(&[1]);
+        let version = message::Version::new(&CLIENT_SUPPORTED_VERSIONS);
         control_stream.send_message(version).await?;
-        let response = message::VersionResponse::recv(control_stream.recv()).await?;
+        let response = match control_stream
+            .recv_message::<message::VersionNegotiation>()
+            .await
+        {
+            Ok(response) => response,
+            // the server closes the connection if there is no common version
+            Err(e) => {
+                return match connection.close_reason() {
+                    Some(quinn::ConnectionError::ApplicationClosed(close))
+                        if close.error_code == CloseCode::VersionMismatch.into() =>
+                    {
+                        Err(Error::NegotiationError)
+                    }
+                    _ => Err(e),
+                }
+            }
+        };
         trace!("negotation response from server {:?}", response);
-        Ok(response.negotiated_version)
+
+        match response {
+            message::VersionNegotiation::VersionResponse(response)
+                if CLIENT_SUPPORTED_VERSIONS.contains(&response.negotiated_version) =>
+            {
+                Ok(response.negotiated_version)
+            }
+            message::VersionNegotiation::VersionResponse(response) => {
+                warn!(
+                    "server picked the unsupported version {}",
+                    response.negotiated_version
+                );
+                connection.close(CloseCode::VersionMismatch.into(), b"unsupported version");
+                Err(Error::NegotiationError)
+            }
+            message::VersionNegotiation::ErrorResponse(response) => {
+                warn!(
+                    "server rejected the version negotiation: {}",
+                    response.message()
+                );
+                Err(Error::NegotiationError)
+            }
+        }
     }
 
-    async fn login<'a>(control_stream: &mut ControlStream) -> Result<(), Error> {
-        let login_request_message =
-            message::LoginRequest::new("test_user".to_string(), "123".to_string());
-        control_stream.send_message(login_request_message).await?;
-        match control_stream
-            .recv_message::<message::LoginResponse>()
-            .await?
-            .is_ok()
+    /// Negotiates the login method with the server and logs in with it. The key is preferred over the password.
+    /// The server answers with a [LoginResponse](message::LoginResponse) afterwards.
+    async fn login(
+        connection: &Connection,
+        control_stream: &mut ControlStream,
+        server_name: &str,
+        certificate: bool,
+        credentials: Option<Arc<dyn CredentialProvider>>,
+        key: Option<KeyCredentials>,
+    ) -> Result<LoginMethod, Error> {
+        let mut methods = Vec::new();
+        if certificate {
+            methods.push(LoginMethod::Certificate);
+        }
+        if key.is_some() {
+            methods.push(LoginMethod::PublicKey);
+        }
+        if credentials.is_some() {
+            methods.push(LoginMethod::Password);
+        }
+        if methods.is_empty() {
+            return Err(Error::CredentialError(
+                "no credentials, key or client certificate were set".to_string(),
+            ));
+        }
+        control_stream
+            .send_message(message::LoginMethods::new(&methods))
+            .await?;
+        let response = control_stream
+            .recv_message::<message::LoginMethodResponse>()
+            .await?;
+
+        match (response.method(), credentials, key) {
+            (Some(LoginMethod::Certificate), _, _) if certificate => Ok(LoginMethod::Certificate),
+            (Some(LoginMethod::Password), Some(credentials), _) => {
+                let credentials = credentials.credentials(server_name).await?;
+                Client::login_password(control_stream, credentials).await?;
+                Ok(LoginMethod::Password)
+            }
+            (Some(LoginMethod::PublicKey), _, Some(key)) => {
+                Client::login_public_key(connection, control_stream, key).await?;
+                Ok(LoginMethod::PublicKey)
+            }
+            (Some(method), _, _) => Err(Error::LoginError(format!(
+                "the server picked the {method} login method, which wasn't offered"
+            ))),
+            (None, _, _) => Err(Error::LoginError(
+                "the server doesn't support any of the offered login methods".to_string(),
+            )),
+        }
+    }
+
+    async fn login_password(
+        control_stream: &mut ControlStream,
+        credentials: Credentials,
+    ) -> Result<(), Error> {
+        if credentials.name().len() > u8::MAX.into()
+            || credentials.password().len() > u8::MAX.into()
         {
-            true => Ok(()),
-            false => Err(Error::LoginError),
+            return Err(Error::CredentialError(format!(
+                "the name and password can't be longer than {} bytes",
+                u8::MAX
+            )));
         }
+        let login_request_message = message::LoginRequest::new(
+            credentials.name().to_string(),
+            credentials.password().to_string(),
+        );
+        control_stream.send_message(login_request_message).await
+    }
+
+    /// Proves that the client owns the key by signing the challenge of the server
+    async fn login_public_key(
+        connection: &Connection,
+        control_stream: &mut ControlStream,
+        key: KeyCredentials,
+    ) -> Result<(), Error> {
+        if key.name().len() > u8::MAX.into() {
+            return Err(Error::CredentialError(format!(
+                "the name can't be longer than {} bytes",
+                u8::MAX
+            )));
+        }
+        let request =
+            message::PublicKeyLoginRequest::new(key.name().to_string(), key.public_key().to_vec());
+        control_stream.send_message(request).await?;
+        let challenge = control_stream
+            .recv_message::<message::LoginChallenge>()
+            .await?;
+        let signature = key.sign(&challenge.signed_data(connection)?);
+        control_stream
+            .send_message(message::LoginSignature::new(signature))
+            .await
     }
 }
 
 impl Client {
-    pub async fn list_files(&mut self) -> Result<Vec<message::ListFileResponse>, Error> {
-        let list_files_request = message::ListFilesRequest::new("/".to_string());
+    /// Lists the files below `path` on the server.
+    ///
+    /// `max_depth` limits how many directory levels are listed. `Some(1)` only lists the files directly in `path`,
+    /// `None` lists the whole tree. `Some(0)` is treated the same as `Some(1)`.
+    pub async fn list_files(
+        &self,
+        path: impl ToString,
+        max_depth: Option<u32>,
+    ) -> Result<Vec<message::ListFileResponse>, Error> {
+        let request_id = self.next_request_id();
+        let list_files_request =
+            message::ListFilesRequest::new(request_id, path.to_string(), max_depth);
+
+        let response = self
+            .send_request(0x01, request_id, list_files_request.to_bytes())
+            .await?;
+
+        self.complete(request_id, response, async {
+            let mut streams = self.recv_streams(request_id, 1).await?;
+            assert!(streams.len() == 1);
+
+            let uni = &mut streams[0];
+            let header = message::ListFileResponseHeader::recv(uni).await?;
+
+            let mut files = Vec::with_capacity(header.num_files as usize);
+            for _ in 0..header.num_files {
+                let file = message::ListFileResponse::recv(uni).await?;
+                files.push(file);
+            }
+
+            Ok(files)
+        })
+        .await
+    }
 
-        let request_id = list_files_request.request_id();
-        trace!("sending request number");
-        self.control_stream.send().write_u16(0x01).await?;
-        self.control_stream.send_message(list_files_request).await?;
-        let (tx, rx) = oneshot::channel();
-        let req = StreamRequest::new(1, request_id, tx);
-        trace!("sending recv_stream_request");
-        self.recv_stream_request
-            .send(req)
-            .map_err(|_| Error::RequestDistributorChannelSendError)?;
-        trace!("got the streams!");
-        let mut streams = rx.await?;
-        assert!(streams.len() == 1);
+    /// Downloads everything below `remote_path` into `local_dir`, recreating the remote directory tree.
+    ///
+    /// The server spreads the files over `num_streams` streams which are received in parallel.
+    ///
+    /// Files that already exist in `local_dir` with the same size and modification time as on the server are skipped.
+    /// Partially received files from an earlier, interrupted download are resumed, unless they changed on the server.
+    ///
+    /// # Panic
+    /// This function panics if `num_streams` is 0
+    pub async fn get_files(
+        &self,
+        remote_path: impl ToString,
+        local_dir: impl AsRef<Path>,
+        num_streams: u16,
+    ) -> Result<(), Error> {
+        assert!(num_streams > 0, "`num_streams` has to be at least 1");
+        let remote_path = remote_path.to_string();
+        let resume_files = Client::resume_files(local_dir.as_ref(), &remote_path).await?;
+        let request_id = self.next_request_id();
+        let get_files_request = message::GetFilesRequest::new(
+            request_id,
+            remote_path,
+            num_streams.into(),
+            resume_files.len() as u32,
+        );
+
+        // the resume files directly follow the request
+        trace!("sending {} resume files", resume_files.len());
+        let mut request = get_files_request.to_bytes();
+        for resume_file in resume_files {
+            request.extend(resume_file.to_bytes());
+        }
+        let response = self.send_request(0x02, request_id, request).await?;
+
+        let local_dir = local_dir.as_ref().to_path_buf();
+        self.complete(request_id, response, async {
+            let streams = self.recv_streams(request_id, num_streams).await?;
+            transfer::recv_files(
+                streams,
+                move |path| files::local_path(&local_dir, path),
+                transfer::never_cancelled(),
+            )
+            .await
+        })
+        .await
+    }
 
-        let uni = &mut streams[0];
-        let header = message::ListFileResponseHeader::recv(uni).await?;
+    /// Collects the complete and partially received files below `remote_path` in `local_dir`.
+    /// Only that part of `local_dir` can be part of the download, so nothing else is walked.
+    async fn resume_files(
+        local_dir: &Path,
+        remote_path: &str,
+    ) -> Result<Vec<message::ResumeFile>, Error> {
+        if !local_dir.is_dir() {
+            return Ok(Vec::new());
+        }
 
-        let mut response = Vec::with_capacity(header.num_files as usize);
-        for _ in 0..header.num_files {
-            let file = message::ListFileResponse::recv(uni).await?;
-            response.push(file);
+        let file_manager = FileManager::new(local_dir)?;
+        // the server rejects an invalid path anyway, so there is nothing to resume for it
+        let offset = match file_manager.resolve(remote_path) {
+            Ok(offset) if local_dir.join(&offset).is_dir() => offset,
+            _ => return Ok(Vec::new()),
+        };
+        let files = file_manager.walk_dir(offset, None).await?;
+        let mut resume_files = HashMap::new();
+        for file in files.into_iter().filter(|file| file.metadata.is_file()) {
+            let path = match files::partial_file_target(&file.relative_path) {
+                // a complete file takes precedence over a left over partial file
+                Some(path) if resume_files.contains_key(&path) => continue,
+                Some(path) => path,
+                None => file.relative_path,
+            };
+
+            let resume_file = message::ResumeFile::new(
+                path.display().to_string(),
+                file.metadata.len(),
+                file.metadata.mtime(),
+            );
+            resume_files.insert(path, resume_file);
         }
 
-        Ok(response)
+        // the server rejects more resume files, the files that are left out are just sent completely
+        Ok(resume_files
+            .into_values()
+            .take(message::MAX_RESUME_FILES as usize)
+            .collect())
+    }
+
+    /// Uploads everything below the local directory `local_dir` to `remote_path` on the server.
+    ///
+    /// The files are spread over `num_streams` streams which are sent in parallel.
+    /// The server stores each file under a temporary name and only renames it once it's complete.
+    ///
+    /// # Panic
+    /// This function panics if `num_streams` is 0
+    pub async fn put_files(
+        &self,
+        local_dir: impl AsRef<Path>,
+        remote_path: impl ToString,
+        num_streams: u16,
+    ) -> Result<(), Error> {
+        assert!(num_streams > 0, "`num_streams` has to be at least 1");
+        let files = FileManager::new(local_dir)?.walk_dir("", None).await?;
+        let request_id = self.next_request_id();
+        let put_files_request =
+            message::PutFilesRequest::new(request_id, remote_path.to_string(), num_streams.into());
+
+        let response = self
+            .send_request(0x03, request_id, put_files_request.to_bytes())
+            .await?;
+
+        // the server responds once all files have been stored
+        self.complete(request_id, response, async {
+            let mut streams = Vec::with_capacity(num_streams as usize);
+            for i in 0..num_streams {
+                let mut stream = self.connection.open_uni().await?;
+                trace!("stream {i} has been opened. sending request_id {request_id}");
+                stream.write_u32(request_id).await?;
+                streams.push(stream);
+            }
+
+            trace!("sending {} files", files.len());
+            let streams = transfer::send_files(files, streams, transfer::never_cancelled()).await?;
+            for mut stream in streams {
+                stream.finish().await?;
+            }
+
+            Ok(())
+        })
+        .await
+    }
+}
+
+impl Client {
+    /// Creates the directory `path` on the server. The parent directory has to exist.
+    pub async fn make_dir(&self, path: impl ToString) -> Result<(), Error> {
+        let request_id = self.next_request_id();
+        let request = message::MakeDirRequest::new(request_id, path.to_string());
+
+        let response = self
+            .send_request(0x04, request_id, request.to_bytes())
+            .await?;
+        Client::check_response(response.await?)
+    }
+
+    /// Removes the directory `path` on the server.
+    /// If `recursive` is false, the directory has to be empty.
+    pub async fn remove_dir(&self, path: impl ToString, recursive: bool) -> Result<(), Error> {
+        let request_id = self.next_request_id();
+        let request = message::RemoveDirRequest::new(request_id, path.to_string(), recursive);
+
+        let response = self
+            .send_request(0x05, request_id, request.to_bytes())
+            .await?;
+        Client::check_response(response.await?)
+    }
+
+    /// Removes the file or symlink `path` on the server. Directories are removed with [remove_dir](Client::remove_dir).
+    pub async fn remove(&self, path: impl ToString) -> Result<(), Error> {
+        let request_id = self.next_request_id();
+        let request = message::RemoveRequest::new(request_id, path.to_string());
+
+        let response = self
+            .send_request(0x06, request_id, request.to_bytes())
+            .await?;
+        Client::check_response(response.await?)
+    }
+
+    /// Renames the file or directory `path` on the server to `new_name`, keeping it in the same directory.
+    pub async fn rename(&self, path: impl ToString, new_name: impl ToString) -> Result<(), Error> {
+        let request_id = self.next_request_id();
+        let request =
+            message::RenameRequest::new(request_id, path.to_string(), new_name.to_string());
+
+        let response = self
+            .send_request(0x07, request_id, request.to_bytes())
+            .await?;
+        Client::check_response(response.await?)
+    }
+
+    /// Moves the file or directory `path` on the server to `destination`.
+    /// If `destination` is an existing directory, `path` is moved into it.
+    pub async fn move_path(
+        &self,
+        path: impl ToString,
+        destination: impl ToString,
+    ) -> Result<(), Error> {
+        let request_id = self.next_request_id();
+        let request =
+            message::MoveRequest::new(request_id, path.to_string(), destination.to_string());
+
+        let response = self
+            .send_request(0x08, request_id, request.to_bytes())
+            .await?;
+        Client::check_response(response.await?)
+    }
+}
+
+/// Always presents the same client certificate
+struct ClientCert(Arc<CertifiedKey>);
+
+impl ResolvesClientCert for ClientCert {
+    fn resolve(&self, _: &[&[u8]], _: &[SignatureScheme]) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.clone())
+    }
+
+    fn has_certs(&self) -> bool {
+        true
     }
 }
 
@@ -269,3 +894,40 @@ impl ServerCertVerifier for DontVerify {
         Ok(ServerCertVerified::assertion())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_resume_files() {
+        let local_dir = std::env::temp_dir().join("qftp_test_resume_files");
+        let _ = std::fs::remove_dir_all(&local_dir);
+        std::fs::create_dir_all(local_dir.join("a/b")).unwrap();
+        std::fs::write(local_dir.join("a/b/file"), "content").unwrap();
+        std::fs::write(local_dir.join("a/.partial.qftp-part"), "content").unwrap();
+        std::fs::write(local_dir.join("other"), "content").unwrap();
+
+        let mut paths: Vec<String> = Client::resume_files(&local_dir, "/a")
+            .await
+            .unwrap()
+            .iter()
+            .map(|file| file.path().to_string())
+            .collect();
+        paths.sort();
+        assert_eq!(paths, ["a/b/file", "a/partial"]);
+
+        assert_eq!(
+            Client::resume_files(&local_dir, "/").await.unwrap().len(),
+            3
+        );
+        for remote_path in ["/missing", "/other", "/../escaped"] {
+            assert!(Client::resume_files(&local_dir, remote_path)
+                .await
+                .unwrap()
+                .is_empty());
+        }
+
+        std::fs::remove_dir_all(&local_dir).unwrap();
+    }
+}
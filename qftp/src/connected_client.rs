@@ -1,80 +1,143 @@
-use crate::auth::{AuthManager, FileStorage, User};
+use crate::auth::{self, AuthError, SharedAuthManager, User};
 use crate::control_stream::ControlStream;
-use crate::files::{FileManager, QFile};
-use crate::message;
-use crate::{message::Message, Error};
-use quinn::{Connection, SendStream};
+use crate::distributor::{self, StreamRequest};
+use crate::files::{self, FileAccess, FileManager, QFile};
+use crate::message::{self, LoginMethod};
+use crate::transfer;
+use crate::{message::Message, CloseCode, Error};
+use quinn::{Connection, RecvStream, SendStream};
+use ring::rand::{SecureRandom, SystemRandom};
+use rustls::Certificate;
+use std::future::Future;
 use std::sync::Arc;
 use tokio::io::{AsyncWrite, AsyncWriteExt};
-use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::sync::{oneshot, watch, Mutex};
 use tokio::task::JoinHandle;
 use tracing::{debug, error, trace, warn};
-const SERVER_SUPPORTED_VERSION: [u8; 1] = [1];
+const SERVER_SUPPORTED_VERSIONS: [u8; 1] = [1];
 
 #[derive(Debug)]
 pub struct ConnectedClient {
     connection: Connection,
-    control_stream: ControlStream,
-    user: Option<User>,
+    /// requests are read from the receiving side of the control stream
+    control_recv: RecvStream,
+    /// responses are sent by the running requests, they are written under the lock so they don't interleave
+    control_send: Arc<Mutex<SendStream>>,
+    version: u8,
+    user: User,
     file_manager: Arc<FileManager>,
     running_requests: Vec<RunningRequest>,
+    recv_stream_request: UnboundedSender<StreamRequest>,
+    max_streams_per_request: u16,
 }
 
 #[derive(Debug)]
 struct RunningRequest {
+    request_id: u32,
     handle: JoinHandle<()>,
-    cancel_ctx: oneshot::Sender<()>,
+    cancel_ctx: watch::Sender<bool>,
 }
 
 #[derive(Debug)]
 struct RequestContext {
     connection: Connection,
+    control_send: Arc<Mutex<SendStream>>,
     file_manager: Arc<FileManager>,
-    #[allow(dead_code)]
-    cancel_ctx: oneshot::Receiver<()>,
+    recv_stream_request: UnboundedSender<StreamRequest>,
+    /// fires once the client cancels the request
+    cancel_ctx: transfer::Cancel,
+    max_streams_per_request: u16,
 }
 
 impl RequestContext {
-    fn new(connected_client: &ConnectedClient) -> (Self, oneshot::Sender<()>) {
-        let (send, recv) = oneshot::channel();
+    fn new(connected_client: &ConnectedClient) -> (Self, watch::Sender<bool>) {
+        let (send, recv) = watch::channel(false);
 
         let ctx = RequestContext {
             connection: connected_client.connection.clone(),
+            control_send: connected_client.control_send.clone(),
             file_manager: connected_client.file_manager.clone(),
+            recv_stream_request: connected_client.recv_stream_request.clone(),
             cancel_ctx: recv,
+            max_streams_per_request: connected_client.max_streams_per_request,
         };
 
         (ctx, send)
     }
+
+    /// Checks the number of streams a request asked for against the limit of the server
+    fn check_num_streams(&self, num_streams: u32) -> Result<u16, Error> {
+        match u16::try_from(num_streams) {
+            Ok(num_streams) if num_streams <= self.max_streams_per_request => Ok(num_streams),
+            _ => Err(Error::TooManyStreams(num_streams)),
+        }
+    }
 }
 
 impl ConnectedClient {
     pub(crate) async fn new(
         connection: Connection,
-        auth_manager: Arc<Mutex<AuthManager<FileStorage>>>,
+        auth_manager: SharedAuthManager,
         file_manager: Arc<FileManager>,
+        login_methods: Arc<[LoginMethod]>,
+        max_streams_per_request: u16,
     ) -> Result<Self, Error> {
         trace!("creating new ConnectedClient");
         let control_stream = connection.accept_bi().await?;
         trace!("accepted the control_stream");
-        let control_stream = ControlStream::new(control_stream.0, control_stream.1);
-        let mut connected_client = ConnectedClient {
+        let mut control_stream = ControlStream::new(control_stream.0, control_stream.1);
+
+        let version = ConnectedClient::negotiate_version(&connection, &mut control_stream).await?;
+        let (user, file_manager) = ConnectedClient::login(
+            &connection,
+            &mut control_stream,
+            auth_manager,
+            &file_manager,
+            &login_methods,
+        )
+        .await?;
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (send, recv) = control_stream.into_parts();
+        let connected_client = ConnectedClient {
             connection,
-            control_stream,
-            user: None,
+            control_recv: recv,
+            control_send: Arc::new(Mutex::new(send)),
+            version,
+            user,
             file_manager,
             running_requests: Vec::new(),
+            recv_stream_request: tx,
+            max_streams_per_request,
         };
 
-        connected_client.negotiate_version().await?;
-        connected_client.user = Some(connected_client.login(auth_manager).await?);
+        tokio::spawn(distributor::run(connected_client.connection.clone(), rx));
         Ok(connected_client)
     }
 
-    pub async fn shutdown(mut self) -> Result<(), Error> {
+    /// The protocol version negotiated with the client
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// The user this client logged in as
+    pub fn user(&self) -> &User {
+        &self.user
+    }
+
+    /// Waits for all running requests, so that their responses can still be sent, and finishes the control stream
+    pub async fn shutdown(self) -> Result<(), Error> {
         debug!("shutting down the server");
+        trace!("waiting for {} requests", self.running_requests.len());
+        for request in self.running_requests {
+            if let Err(e) = request.handle.await {
+                error!("JoinError while waiting for a running request: {e}");
+            }
+        }
+
         trace!("calling finish on the SendStream of the ControlStream");
-        match self.control_stream.send().finish().await {
+        match self.control_send.lock().await.finish().await {
             Ok(()) => (),
             Err(quinn::WriteError::ConnectionLost(quinn::ConnectionError::ApplicationClosed(
                 e,
@@ -90,137 +153,427 @@ impl ConnectedClient {
         };
         trace!("calling finish on the SendStream of the ControlStream returned");
 
-        trace!("checking all requests");
-        for request in self.running_requests {
-            if !request.handle.is_finished() {
-                request.cancel_ctx.send(()).unwrap();
-            }
-        }
         Ok(())
     }
 
+    /// Logs the client in and returns its user together with the [FileManager] scoped to it
     async fn login(
-        &mut self,
-        auth_manager: Arc<Mutex<AuthManager<FileStorage>>>,
-    ) -> Result<User, Error> {
-        let login_request_message: message::LoginRequest =
-            self.control_stream.recv_message().await?;
-        let mut auth_manager = auth_manager.lock().await;
-
-        match (*auth_manager)
-            .get_user(
-                login_request_message.name(),
-                login_request_message.password(),
-            )
-            .await
-        {
-            Ok(user) => {
-                self.control_stream
+        connection: &Connection,
+        control_stream: &mut ControlStream,
+        auth_manager: SharedAuthManager,
+        file_manager: &FileManager,
+        login_methods: &[LoginMethod],
+    ) -> Result<(User, Arc<FileManager>), Error> {
+        // the certificate login is offered by the client, since only the TLS state of the server tells if
+        // a certificate was verified. On a resumed session the client doesn't know whether it presented one
+        let certificate = ConnectedClient::client_certificate(connection);
+        let methods: message::LoginMethods = control_stream.recv_message().await?;
+        let method = methods.methods().find(|method| match method {
+            LoginMethod::Certificate => certificate.is_some(),
+            method => login_methods.contains(method),
+        });
+        control_stream
+            .send_message(message::LoginMethodResponse::new(method))
+            .await?;
+        let (result, failed_reason) = match (method, certificate) {
+            (Some(LoginMethod::Certificate), Some(certificate)) => (
+                auth_manager
+                    .lock()
+                    .await
+                    .get_user_by_certificate(&certificate)
+                    .await,
+                "unknown client certificate",
+            ),
+            (Some(LoginMethod::Password), _) => (
+                ConnectedClient::login_password(connection, control_stream, &auth_manager).await?,
+                "invalid user name or password",
+            ),
+            (Some(LoginMethod::PublicKey), _) => (
+                ConnectedClient::login_public_key(connection, control_stream, &auth_manager)
+                    .await?,
+                "invalid user name or key",
+            ),
+            _ => {
+                let reason = "no common login method";
+                warn!("login failed: {reason}");
+                control_stream.finish().await?;
+                connection.close(CloseCode::LoginFailed.into(), reason.as_bytes());
+                return Err(Error::LoginError(reason.to_string()));
+            }
+        };
+
+        // a root that can't be used fails the login, the user couldn't do anything anyway
+        let result = result.and_then(|user| {
+            let file_manager = ConnectedClient::user_file_manager(file_manager, &user)?;
+            Ok((user, file_manager))
+        });
+        match result {
+            Ok((user, file_manager)) => {
+                control_stream
                     .send_message(message::LoginResponse::new(true))
                     .await?;
-                Ok(user)
+                Ok((user, Arc::new(file_manager)))
             }
             Err(e) => {
-                self.control_stream
-                    .send_message(message::LoginResponse::new(false))
+                // unknown users and wrong passwords get the same reason, so names can't be probed
+                let reason = match e {
+                    Error::AuthenticationError(AuthError::UserNotFound)
+                    | Error::AuthenticationError(AuthError::WrongPassword)
+                    | Error::AuthenticationError(AuthError::WrongKey) => failed_reason,
+                    Error::AuthenticationError(AuthError::LockedOut(_)) => {
+                        "too many failed logins, try again later"
+                    }
+                    _ => "internal server error",
+                };
+                warn!("login failed: {e}");
+                control_stream
+                    .send_message(message::LoginResponse::failed(reason.to_string()))
                     .await?;
+                // the response has to reach the client before the connection is closed
+                control_stream.finish().await?;
+                connection.close(CloseCode::LoginFailed.into(), reason.as_bytes());
                 Err(e)
             }
         }
     }
 
-    pub async fn next_request(&mut self) -> Result<(), Error> {
-        match message::Request::next_request(self.control_stream.recv()).await? {
-            message::Request::ListFileRequest(request) => {
-                let (ctx, send) = RequestContext::new(self);
+    /// Reads the [LoginRequest](message::LoginRequest) and checks the password
+    async fn login_password(
+        connection: &Connection,
+        control_stream: &mut ControlStream,
+        auth_manager: &SharedAuthManager,
+    ) -> Result<Result<User, Error>, Error> {
+        let request: message::LoginRequest = control_stream.recv_message().await?;
+        let address = connection.remote_address().ip();
+        let user = auth_manager
+            .lock()
+            .await
+            .start_login(request.name(), address)
+            .await;
+        let user = match user {
+            Ok(user) => user,
+            Err(e) => return Ok(Err(e)),
+        };
 
-                let handle = tokio::spawn(async move {
-                    match ConnectedClient::handle_list_files_request(ctx, request).await {
-                        Ok(()) => {
-                            debug!("ListFileRequest successfully handled")
-                        }
-                        Err(e) => {
-                            error!("ListFileRequest failed: {e}")
-                        }
-                    }
-                });
+        // the hash is verified without holding the auth manager, so other logins don't have to wait for it
+        let result = auth::check_password(user, request.password()).await;
+        auth_manager
+            .lock()
+            .await
+            .record_login(request.name(), address, &result);
+        Ok(result)
+    }
+
+    /// Reads the [PublicKeyLoginRequest](message::PublicKeyLoginRequest), sends a random challenge and verifies
+    /// the signature of the client.
+    ///
+    /// The challenge is sent even if the user doesn't exist, so a client can't tell whether it does.
+    async fn login_public_key(
+        connection: &Connection,
+        control_stream: &mut ControlStream,
+        auth_manager: &SharedAuthManager,
+    ) -> Result<Result<User, Error>, Error> {
+        let request: message::PublicKeyLoginRequest = control_stream.recv_message().await?;
+        let mut challenge = vec![0; 32];
+        SystemRandom::new().fill(&mut challenge).map_err(|_| {
+            rustls::Error::General("failed to generate the login challenge".to_string())
+        })?;
+        let challenge = message::LoginChallenge::new(challenge);
+        let data = challenge.signed_data(connection)?;
+        control_stream.send_message(challenge).await?;
+        let signature: message::LoginSignature = control_stream.recv_message().await?;
+
+        let address = connection.remote_address().ip();
+        let user = auth_manager
+            .lock()
+            .await
+            .start_login(request.name(), address)
+            .await;
+        let user = match user {
+            Ok(user) => user,
+            Err(e) => return Ok(Err(e)),
+        };
 
-                self.running_requests.push(RunningRequest {
-                    handle,
-                    cancel_ctx: send,
+        let result =
+            auth::check_public_key(user, request.public_key(), &data, signature.signature());
+        auth_manager
+            .lock()
+            .await
+            .record_login(request.name(), address, &result);
+        Ok(result)
+    }
+
+    /// The end-entity certificate the client presented, it was already verified during the handshake
+    fn client_certificate(connection: &Connection) -> Option<Certificate> {
+        let certificates = connection
+            .peer_identity()?
+            .downcast::<Vec<Certificate>>()
+            .ok()?;
+        certificates.into_iter().next()
+    }
+
+    /// Scopes `file_manager` to the root of `user` and the files it's allowed to access
+    fn user_file_manager(file_manager: &FileManager, user: &User) -> Result<FileManager, Error> {
+        let access = FileAccess::new(user.uid(), user.gid().to_vec());
+        match user.root() {
+            Some(root) => Ok(file_manager.with_root(root)?.with_access(access)),
+            None => Ok(file_manager.with_access(access)),
+        }
+    }
+
+    /// Handles requests until the client finishes the control stream, then shuts down and closes the connection
+    pub async fn run(mut self) -> Result<(), Error> {
+        while self.next_request().await? {}
+        debug!("no more requests are read from the control stream");
+
+        let connection = self.connection.clone();
+        self.shutdown().await?;
+        connection.close(CloseCode::Ok.into(), b"");
+        Ok(())
+    }
+
+    /// Reads and handles the next request. Requests transferring files keep running in the background.
+    ///
+    /// Returns `false` if the client finished the control stream instead of sending another request,
+    /// or if the rest of the control stream can't be read.
+    pub async fn next_request(&mut self) -> Result<bool, Error> {
+        let request = match message::Request::next_request(&mut self.control_recv).await? {
+            Some(request) => request,
+            None => return Ok(false),
+        };
+        match request {
+            message::Request::ListFileRequest(request) => {
+                self.spawn_request("ListFileRequest", request.request_id(), |ctx| {
+                    ConnectedClient::handle_list_files_request(ctx, request)
                 });
             }
-            message::Request::GetFilesRequest(request) => {
-                let (ctx, send) = RequestContext::new(self);
-
-                let handle = tokio::spawn(async move {
-                    match ConnectedClient::handle_get_files_request(ctx, request).await {
-                        Ok(()) => {
-                            debug!("GetFilesRequest successfully handled")
-                        }
-                        Err(e) => {
-                            error!("GetFilesRequest failed: {e}")
-                        }
-                    }
+            message::Request::GetFilesRequest(request, resume_files) => {
+                self.spawn_request("GetFilesRequest", request.request_id(), |ctx| {
+                    ConnectedClient::handle_get_files_request(ctx, request, resume_files)
                 });
-
-                self.running_requests.push(RunningRequest {
-                    handle,
-                    cancel_ctx: send,
+            }
+            // the resume files following the request weren't read, so no other request can be read after it
+            message::Request::TooManyResumeFiles(request) => {
+                let error = Error::TooManyResumeFiles(request.num_resume_files());
+                self.send_response("GetFilesRequest", request.request_id(), Err(error))
+                    .await?;
+                return Ok(false);
+            }
+            message::Request::PutFilesRequest(request) => {
+                self.spawn_request("PutFilesRequest", request.request_id(), |ctx| {
+                    ConnectedClient::handle_put_files_request(ctx, request)
                 });
             }
+            // requests that don't transfer any data are handled directly
+            message::Request::MakeDirRequest(request) => {
+                let result = self.file_manager.make_dir(request.path()).await;
+                self.send_response(
+                    "MakeDirRequest",
+                    request.request_id(),
+                    result.map_err(Error::from),
+                )
+                .await?;
+            }
+            message::Request::RemoveDirRequest(request) => {
+                let result = self
+                    .file_manager
+                    .remove_dir(request.path(), request.recursive())
+                    .await;
+                self.send_response(
+                    "RemoveDirRequest",
+                    request.request_id(),
+                    result.map_err(Error::from),
+                )
+                .await?;
+            }
+            message::Request::RemoveRequest(request) => {
+                let result = self.file_manager.remove_file(request.path()).await;
+                self.send_response(
+                    "RemoveRequest",
+                    request.request_id(),
+                    result.map_err(Error::from),
+                )
+                .await?;
+            }
+            message::Request::RenameRequest(request) => {
+                let result = self
+                    .file_manager
+                    .rename(request.path(), request.new_name())
+                    .await;
+                self.send_response(
+                    "RenameRequest",
+                    request.request_id(),
+                    result.map_err(Error::from),
+                )
+                .await?;
+            }
+            message::Request::CancelRequest(request) => self.cancel_request(request.request_id()),
+            message::Request::MoveRequest(request) => {
+                let result = self
+                    .file_manager
+                    .move_path(request.path(), request.destination())
+                    .await;
+                self.send_response(
+                    "MoveRequest",
+                    request.request_id(),
+                    result.map_err(Error::from),
+                )
+                .await?;
+            }
         }
 
-        Ok(())
+        Ok(true)
+    }
+
+    /// Runs `handler` in a new task and answers the request once it's done
+    fn spawn_request<F, Fut>(&mut self, request_name: &'static str, request_id: u32, handler: F)
+    where
+        F: FnOnce(RequestContext) -> Fut,
+        Fut: Future<Output = Result<(), Error>> + Send + 'static,
+    {
+        // a client can send any number of requests, so the finished ones are dropped
+        self.running_requests
+            .retain(|request| !request.handle.is_finished());
+
+        let (ctx, send) = RequestContext::new(self);
+        let control_send = ctx.control_send.clone();
+        let cancel = ctx.cancel_ctx.clone();
+        let request = handler(ctx);
+
+        let handle = tokio::spawn(async move {
+            let result = match request.await {
+                // the client stops the streams of a request it cancelled, which can fail the request before it noticed the cancel
+                Err(_) if *cancel.borrow() => Err(Error::Cancelled),
+                result => result,
+            };
+            if let Err(e) =
+                ConnectedClient::send_response_impl(&control_send, request_name, request_id, result)
+                    .await
+            {
+                error!("failed to send the response to {request_name}: {e}");
+            }
+        });
+
+        self.running_requests.push(RunningRequest {
+            request_id,
+            handle,
+            cancel_ctx: send,
+        });
+    }
+
+    /// Signals the running request `request_id` to stop. It answers the request itself once it stopped.
+    fn cancel_request(&self, request_id: u32) {
+        let request = self
+            .running_requests
+            .iter()
+            .find(|request| request.request_id == request_id && !request.handle.is_finished());
+        match request {
+            Some(request) => {
+                debug!("cancelling request {request_id}");
+                // the request might finish in the meantime, then there is nothing to cancel
+                let _ = request.cancel_ctx.send(true);
+            }
+            None => debug!("request {request_id} isn't running, it can't be cancelled"),
+        }
+    }
+
+    async fn send_response(
+        &self,
+        request_name: &str,
+        request_id: u32,
+        result: Result<(), Error>,
+    ) -> Result<(), Error> {
+        ConnectedClient::send_response_impl(&self.control_send, request_name, request_id, result)
+            .await
+    }
+
+    /// Answers the request with a [StatusResponse](message::StatusResponse) if it succeeded
+    /// or with an [ErrorResponse](message::ErrorResponse) otherwise
+    async fn send_response_impl(
+        control_send: &Mutex<SendStream>,
+        request_name: &str,
+        request_id: u32,
+        result: Result<(), Error>,
+    ) -> Result<(), Error> {
+        match &result {
+            Ok(()) => debug!("{request_name} successfully handled"),
+            Err(e) => error!("{request_name} failed: {e}"),
+        }
+
+        let response = message::Response::new(request_id, &result);
+        trace!("sending {response:?}");
+        response.send(&mut *control_send.lock().await).await
     }
 
     async fn handle_list_files_request(
-        ctx: RequestContext,
+        mut ctx: RequestContext,
         request: message::ListFilesRequest,
     ) -> Result<(), Error> {
+        let files: Vec<message::ListFileResponse> = ctx
+            .file_manager
+            .walk_dir(request.path(), request.max_depth())
+            .await?
+            .into_iter()
+            .map(|e| e.into())
+            .collect();
+
         trace!("got request {request:#?}\nopening new uni stream");
         let mut uni = ctx.connection.open_uni().await?;
 
+        let result = tokio::select! {
+            result = ConnectedClient::send_list(&mut uni, request.request_id(), files) => result,
+            _ = transfer::cancelled(&mut ctx.cancel_ctx) => Err(Error::Cancelled),
+        };
+        if let Err(Error::Cancelled) = result {
+            debug!("cancelled listing files, aborting the stream");
+            transfer::Abort::abort(&mut uni);
+        }
+        result?;
+
+        trace!("done sending files");
+        uni.finish().await?;
+        Ok(())
+    }
+
+    async fn send_list(
+        uni: &mut SendStream,
+        request_id: u32,
+        files: Vec<message::ListFileResponse>,
+    ) -> Result<(), Error> {
         trace!("opened new uni stream. Sending request_id");
-        uni.write_u32(request.request_id()).await?;
+        uni.write_u32(request_id).await?;
         trace!("wrote the request ID");
 
-        let files: Vec<message::ListFileResponse> = ctx
-            .file_manager
-            .walk_dir("")
-            .await
-            .unwrap()
-            .into_iter()
-            .map(|e| e.into())
-            .collect();
         let msg = message::ListFileResponseHeader {
             num_files: files.len() as u32,
         };
 
         trace!("sending ListFileResponseHeader {msg:?}");
-        msg.send(&mut uni).await?;
+        msg.send(uni).await?;
 
         trace!("sending files");
         for file in files {
-            file.send(&mut uni).await?;
+            file.send(uni).await?;
         }
 
-        trace!("done sending files");
-        uni.finish().await?;
         Ok(())
     }
 
     async fn handle_get_files_request(
         ctx: RequestContext,
         request: message::GetFilesRequest,
+        resume_files: Vec<message::ResumeFile>,
     ) -> Result<(), Error> {
+        let num_streams = ctx.check_num_streams(request.num_streams())?;
+        // the files are collected before any stream is opened, so that an invalid request fails without any streams
+        let files = ctx.file_manager.walk_dir(request.path(), None).await?;
+
         // the purpose of this function is to basically just open the streams and write the reqeust ID
         // the actual logic is implemented in handle_get_files_request_impl
         let mut streams = Vec::new();
         let mut join_set = tokio::task::JoinSet::new();
         trace!("created the join set, spawning streams");
-        // TODO: have some upper limit for amount of channels to be spawned to stop a DOS attack
-        for i in 0..request.num_streams() {
+        for i in 0..num_streams {
             trace!("spawning stream {i}");
             let connection = ctx.connection.clone();
             let request_id = request.request_id();
@@ -247,86 +600,124 @@ impl ConnectedClient {
 
         trace!("joining all stream creation threads");
         while let Some(stream) = join_set.join_next().await {
-            streams.push(stream.expect("JoinError")?);
+            streams.push(stream.map_err(transfer::join_error)??);
         }
 
         trace!("all streams collected, calling handle_get_files_request_impl");
 
-        ConnectedClient::handle_get_files_request_impl(ctx.file_manager.clone(), streams, request)
-            .await
+        let streams = ConnectedClient::handle_get_files_request_impl(
+            files,
+            streams,
+            resume_files,
+            ctx.cancel_ctx,
+        )
+        .await?;
+
+        trace!("finishing {} streams", streams.len());
+        for mut stream in streams {
+            stream.finish().await?;
+        }
+
+        Ok(())
     }
 
+    /// Sends `files` over `streams`, skipping what the client already has
     async fn handle_get_files_request_impl<T>(
-        file_manager: Arc<FileManager>,
-        mut streams: Vec<T>,
-        request: message::GetFilesRequest,
-    ) -> Result<(), Error>
+        files: Vec<QFile>,
+        streams: Vec<T>,
+        resume_files: Vec<message::ResumeFile>,
+        cancel: transfer::Cancel,
+    ) -> Result<Vec<T>, Error>
     where
-        T: AsyncWrite + Send + Sync + Unpin + 'static,
+        T: AsyncWrite + transfer::Abort + Send + Sync + Unpin + 'static,
     {
-        let files = file_manager.walk_dir("").await.unwrap();
-        let mut join_set: tokio::task::JoinSet<Result<(), Error>> = tokio::task::JoinSet::new();
-        let mut channels = Vec::new();
-        for i in 0..request.num_streams() {
-            // TODO: it's probably better to use a not unbounded channel here(?)
-            let (send, mut recv) = mpsc::unbounded_channel::<QFile>();
-            channels.push(send);
-            trace!("spawning thread {i} to handle file sending");
-            let mut writer = streams
-                .pop()
-                .expect("we have less streams than requested in num_streams");
+        let files = transfer::resume_files(files, resume_files);
 
-            join_set.spawn(async move {
-                while let Some(mut file) = recv.recv().await {
-                    trace!("Got {file:?} to send");
-                    file.send(&mut writer).await?;
-                }
-                trace!("Got None");
-                Ok(())
-            });
-        }
-
-        // TODO: this is pretty inefficient. A better way would probably be to use select or something
-        // and make the threads signal when they are ready for another message
-        for (i, file) in files.into_iter().enumerate() {
-            channels[i % channels.len()]
-                .send(file)
-                .expect("couldn't send");
-        }
-
-        for channel in channels {
-            drop(channel);
-        }
-
-        while let Some(res) = join_set.join_next().await {
-            match res {
-                Ok(Ok(())) => (),
-                Ok(Err(e)) => error!("Error in handle_get_files_request_impl worker thread: {e}"),
-                Err(e) => error!(
-                    "JoinError while joining handle_get_files_request_impl worker threads: {e}"
-                ),
-            }
-        }
+        transfer::send_files(files, streams, cancel).await
+    }
 
-        Ok(())
+    async fn handle_put_files_request(
+        mut ctx: RequestContext,
+        request: message::PutFilesRequest,
+    ) -> Result<(), Error> {
+        let num_streams = ctx.check_num_streams(request.num_streams())?;
+
+        // the streams are taken before the path is checked. If it's invalid they are dropped,
+        // which stops the client from sending instead of leaving the streams unread
+        let (tx, rx) = oneshot::channel();
+        let req = StreamRequest::new(num_streams, request.request_id(), tx);
+        trace!("sending recv_stream_request");
+        ctx.recv_stream_request
+            .send(req)
+            .map_err(|_| Error::RequestDistributorChannelSendError)?;
+        // a client that cancels might never open all of its streams
+        let streams = tokio::select! {
+            streams = rx => streams?,
+            _ = transfer::cancelled(&mut ctx.cancel_ctx) => return Err(Error::Cancelled),
+        };
+        trace!("got all {} streams, receiving files", streams.len());
+        let path = ctx.file_manager.resolve(request.path())?;
+
+        // every file path is resolved on its own, a symlink below `path` could otherwise be used to escape the base path
+        let file_manager = ctx.file_manager.clone();
+        transfer::recv_files(
+            streams,
+            move |file_path| file_manager.join_writable(files::local_path(&path, file_path)?),
+            ctx.cancel_ctx,
+        )
+        .await
     }
 
-    async fn negotiate_version(&mut self) -> Result<(), Error> {
+    /// Answers the [Version](message::Version) message of the client with the highest version both sides support.
+    /// If there is none, the client is sent an error and the connection is closed.
+    async fn negotiate_version(
+        connection: &Connection,
+        control_stream: &mut ControlStream,
+    ) -> Result<u8, Error> {
         debug!("doing version negotation");
-        let version = message::Version::recv(self.control_stream.recv()).await?;
+        let version = message::Version::recv(control_stream.recv()).await?;
         trace!("negotation message from client {:?}", version);
-        for version in version.versions() {
-            if SERVER_SUPPORTED_VERSION.contains(version) {
+
+        match select_version(version.versions(), &SERVER_SUPPORTED_VERSIONS) {
+            Some(version) => {
                 trace!("version {} negotiated", version);
-                let version_response = message::VersionResponse::new(*version);
-                self.control_stream.send_message(version_response).await?;
+                let response = message::VersionNegotiation::VersionResponse(
+                    message::VersionResponse::new(version),
+                );
+                control_stream.send_message(response).await?;
+                debug!("finished version negotiation");
+
+                Ok(version)
+            }
+            None => {
+                let error = Error::NegotiationError;
+                warn!(
+                    "client only supports the versions {:?}, closing the connection",
+                    version.versions()
+                );
+                let response = message::VersionNegotiation::ErrorResponse(
+                    message::ErrorResponse::new(0, &error),
+                );
+                control_stream.send_message(response).await?;
+                // the error has to reach the client before the connection is closed
+                control_stream.finish().await?;
+                connection.close(CloseCode::VersionMismatch.into(), b"no common version");
+
+                Err(error)
             }
         }
-        debug!("finished version negotiation");
-        Ok(())
     }
 }
 
+/// Picks the highest version that is supported by both the client and the server
+fn select_version(client_versions: &[u8], server_versions: &[u8]) -> Option<u8> {
+    client_versions
+        .iter()
+        .filter(|version| server_versions.contains(version))
+        .max()
+        .copied()
+}
+
 #[cfg(test)]
 mod test {
     use tracing::Level;
@@ -342,13 +733,27 @@ mod test {
             .with_max_level(Level::TRACE)
             .with_env_filter(env_filter)
             .init();
-        let request = message::GetFilesRequest::new(String::from("a"), 1);
         let path = format!("{}/tests/walk_dir", env!("CARGO_MANIFEST_DIR"));
         let file_manager =
             Arc::new(FileManager::new(path).expect("expect creating a file manager not to fail"));
+        let files = file_manager.walk_dir("a", None).await.unwrap();
         let a = vec![vec![]];
-        ConnectedClient::handle_get_files_request_impl(file_manager, a, request)
-            .await
-            .expect("expect this not to panic");
+        ConnectedClient::handle_get_files_request_impl(
+            files,
+            a,
+            Vec::new(),
+            transfer::never_cancelled(),
+        )
+        .await
+        .expect("expect this not to panic");
+    }
+
+    #[test]
+    fn test_select_version() {
+        assert_eq!(select_version(&[1], &[1]), Some(1));
+        assert_eq!(select_version(&[1, 3, 5], &[1, 2, 3]), Some(3));
+        assert_eq!(select_version(&[3, 2], &[1, 2, 3]), Some(3));
+        assert_eq!(select_version(&[4, 5], &[1, 2, 3]), None);
+        assert_eq!(select_version(&[], &[1]), None);
     }
 }
@@ -45,8 +45,15 @@ impl ControlStream {
         &mut self.recv
     }
 
-    pub(crate) fn send(&mut self) -> &mut SendStream {
-        &mut self.send
+    /// Finishes the sending side and waits until the peer received everything
+    pub(crate) async fn finish(&mut self) -> Result<(), Error> {
+        self.send.finish().await?;
+        Ok(())
+    }
+
+    /// Splits the ControlStream, so that messages can be sent and received concurrently
+    pub(crate) fn into_parts(self) -> (SendStream, RecvStream) {
+        (self.send, self.recv)
     }
 
     pub async fn send_message<T: Message + Send>(&mut self, message: T) -> Result<(), Error> {
@@ -0,0 +1,253 @@
+use crate::Error;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use std::{fmt, path::PathBuf, sync::Arc};
+
+/// The name and password a [Client](crate::Client) logs in with
+#[derive(Clone)]
+pub struct Credentials {
+    name: String,
+    password: String,
+}
+
+// Implement Debug manually since we don't want the password to be logged
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("name", &self.name)
+            .field("password", &"***")
+            .finish()
+    }
+}
+
+impl Credentials {
+    pub fn new(name: String, password: String) -> Self {
+        Credentials { name, password }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn password(&self) -> &str {
+        &self.password
+    }
+}
+
+/// The name and Ed25519 key pair a [Client](crate::Client) logs in with instead of a password.
+/// The public key has to be [authorized](crate::auth::AuthManager::set_authorized_keys) for the user.
+#[derive(Clone)]
+pub struct KeyCredentials {
+    name: String,
+    key_pair: Arc<Ed25519KeyPair>,
+}
+
+// Implement Debug manually since we don't want the private key to be logged
+impl fmt::Debug for KeyCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyCredentials")
+            .field("name", &self.name)
+            .field("public_key", &self.key_pair.public_key())
+            .finish()
+    }
+}
+
+impl KeyCredentials {
+    /// `pkcs8` is the DER encoded private key, e.g. created by `openssl genpkey -algorithm ed25519 -outform der`
+    pub fn from_pkcs8(name: String, pkcs8: &[u8]) -> Result<Self, Error> {
+        let key_pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(pkcs8)
+            .map_err(|e| Error::CredentialError(format!("invalid Ed25519 private key: {e}")))?;
+        Ok(KeyCredentials {
+            name,
+            key_pair: Arc::new(key_pair),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        self.key_pair.public_key().as_ref()
+    }
+
+    pub(crate) fn sign(&self, data: &[u8]) -> Vec<u8> {
+        self.key_pair.sign(data).as_ref().to_vec()
+    }
+}
+
+/// Hook for supplying the [Credentials] of a [Client](crate::Client).
+///
+/// It is called once per connection, after the version negotiation, with the server name passed to
+/// [ClientBuilder::set_addr](crate::ClientBuilder::set_addr).
+/// Implement it to e.g. prompt the user interactively.
+#[async_trait::async_trait]
+pub trait CredentialProvider: fmt::Debug + Send + Sync {
+    async fn credentials(&self, server_name: &str) -> Result<Credentials, Error>;
+}
+
+#[async_trait::async_trait]
+impl CredentialProvider for Credentials {
+    async fn credentials(&self, _server_name: &str) -> Result<Credentials, Error> {
+        Ok(self.clone())
+    }
+}
+
+/// Reads the [Credentials] from environment variables
+#[derive(Debug)]
+pub struct EnvCredentials {
+    name_var: String,
+    password_var: String,
+}
+
+impl EnvCredentials {
+    pub fn new(name_var: String, password_var: String) -> Self {
+        EnvCredentials {
+            name_var,
+            password_var,
+        }
+    }
+}
+
+impl Default for EnvCredentials {
+    /// Reads the name from `QFTP_USER` and the password from `QFTP_PASSWORD`
+    fn default() -> Self {
+        EnvCredentials::new("QFTP_USER".to_string(), "QFTP_PASSWORD".to_string())
+    }
+}
+
+#[async_trait::async_trait]
+impl CredentialProvider for EnvCredentials {
+    async fn credentials(&self, _server_name: &str) -> Result<Credentials, Error> {
+        let var = |name: &str| {
+            std::env::var(name)
+                .map_err(|e| Error::CredentialError(format!("can't read `{name}`: {e}")))
+        };
+        Ok(Credentials::new(
+            var(&self.name_var)?,
+            var(&self.password_var)?,
+        ))
+    }
+}
+
+/// Reads the [Credentials] from a netrc-style file.
+///
+/// The file consists of `machine <server name> login <name> password <password>` entries.
+/// A `default login <name> password <password>` entry is used for every server without its own entry.
+#[derive(Debug)]
+pub struct NetrcCredentials {
+    path: PathBuf,
+}
+
+impl NetrcCredentials {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        NetrcCredentials { path: path.into() }
+    }
+}
+
+#[async_trait::async_trait]
+impl CredentialProvider for NetrcCredentials {
+    async fn credentials(&self, server_name: &str) -> Result<Credentials, Error> {
+        let netrc = tokio::fs::read_to_string(&self.path).await?;
+        parse_netrc(&netrc, server_name)?.ok_or_else(|| {
+            Error::CredentialError(format!(
+                "{:?} contains no entry for `{server_name}`",
+                self.path
+            ))
+        })
+    }
+}
+
+/// Returns the credentials of the first entry for `server_name`, or of the `default` entry if there is none
+fn parse_netrc(netrc: &str, server_name: &str) -> Result<Option<Credentials>, Error> {
+    #[derive(Default)]
+    struct Entry<'a> {
+        machine: Option<&'a str>,
+        login: Option<&'a str>,
+        password: Option<&'a str>,
+    }
+
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut tokens = netrc.split_whitespace();
+    while let Some(token) = tokens.next() {
+        let mut value = || {
+            tokens.next().ok_or_else(|| {
+                Error::CredentialError(format!("missing value for `{token}` in netrc file"))
+            })
+        };
+        match token {
+            "machine" => entries.push(Entry {
+                machine: Some(value()?),
+                ..Default::default()
+            }),
+            "default" => entries.push(Entry::default()),
+            "login" | "password" | "account" => {
+                let value = value()?;
+                let entry = entries.last_mut().ok_or_else(|| {
+                    Error::CredentialError(format!("`{token}` outside of an entry in netrc file"))
+                })?;
+                match token {
+                    "login" => entry.login = Some(value),
+                    "password" => entry.password = Some(value),
+                    _ => (),
+                }
+            }
+            token => {
+                return Err(Error::CredentialError(format!(
+                    "unknown token `{token}` in netrc file"
+                )))
+            }
+        }
+    }
+
+    let entry = entries
+        .iter()
+        .find(|entry| entry.machine == Some(server_name))
+        .or_else(|| entries.iter().find(|entry| entry.machine.is_none()));
+    match entry {
+        Some(Entry {
+            login: Some(login),
+            password: Some(password),
+            ..
+        }) => Ok(Some(Credentials::new(
+            login.to_string(),
+            password.to_string(),
+        ))),
+        Some(_) => Err(Error::CredentialError(format!(
+            "the netrc entry for `{server_name}` is missing the login or password"
+        ))),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_netrc() {
+        let netrc = "
+            machine dev.local login test_user password 123
+            machine other.local
+                login other_user
+                password 456
+            default login anonymous password anonymous
+        ";
+
+        let credentials = parse_netrc(netrc, "other.local").unwrap().unwrap();
+        assert_eq!(credentials.name(), "other_user");
+        assert_eq!(credentials.password(), "456");
+        let credentials = parse_netrc(netrc, "unknown.local").unwrap().unwrap();
+        assert_eq!(credentials.name(), "anonymous");
+
+        let netrc = "machine dev.local login test_user password 123";
+        assert!(parse_netrc(netrc, "unknown.local").unwrap().is_none());
+        assert!(matches!(
+            parse_netrc("machine dev.local login test_user", "dev.local"),
+            Err(Error::CredentialError(_))
+        ));
+        assert!(matches!(
+            parse_netrc("login test_user", "dev.local"),
+            Err(Error::CredentialError(_))
+        ));
+    }
+}
@@ -4,11 +4,15 @@ use quinn::{Connection, RecvStream};
 use tokio::{
     io::AsyncReadExt,
     sync::{mpsc::UnboundedReceiver, oneshot::Sender},
+    task::JoinSet,
 };
 
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Duration};
 use tracing::{debug, error, trace};
 
+/// How long a new stream has to send its request id before it is dropped
+const REQUEST_ID_TIMEOUT: Duration = Duration::from_secs(10);
+
 #[derive(Debug)]
 pub(crate) struct StreamRequest {
     num_streams: u16,
@@ -38,12 +42,16 @@ pub(crate) async fn run(connection: Connection, mut channel: UnboundedReceiver<S
     trace!("starting the stream distributor");
     let mut messages = HashMap::new();
     let mut recv_stream_buffer = HashMap::new();
+    // the number of streams that are still expected for requests that stopped waiting for them
+    let mut discarded = HashMap::new();
+    // the request ids are read in their own tasks, a stream that never sends one doesn't hold up the other streams
+    let mut pending_streams = JoinSet::new();
 
     // create a loop with a tokio::select! inside that checks connection.accept_{uni,bi} and channel.recv()
     loop {
         tokio::select! {
             s = connection.accept_uni() => {
-                let mut s = match s {
+                let s = match s {
                     Ok(s) => s,
                     Err(quinn::ConnectionError::ApplicationClosed(e)) => {
                         if e.error_code == quinn::VarInt::from_u32(0) {
@@ -60,12 +68,29 @@ pub(crate) async fn run(connection: Connection, mut channel: UnboundedReceiver<S
                     }
                 };
                 trace!("accepted new uni stream");
-                let request_id = read_request_id(&mut s).await.unwrap();
+                pending_streams.spawn(read_request_id(s));
+            }
+
+            Some(res) = pending_streams.join_next() => {
+                // the stream might have been reset before the request id was sent, e.g. if the request was cancelled
+                let (request_id, s) = match res {
+                    Ok(Ok(res)) => res,
+                    Ok(Err(e)) => {
+                        debug!("failed to read the request id of a stream: {e}");
+                        continue;
+                    }
+                    Err(e) => {
+                        error!("JoinError while reading the request id of a stream: {e}");
+                        continue;
+                    }
+                };
+                remove_closed(&mut messages, &mut discarded);
+                if discard_stream(request_id, &mut discarded) {
+                    continue;
+                }
                 check_buffer(request_id, &mut messages, &mut recv_stream_buffer);
                 match handle_stream(request_id, s, &mut messages, &mut recv_stream_buffer) {
-                    Some(request) => {
-                        request.response_sender.send(request.response).unwrap();
-                    },
+                    Some(request) => send_response(request),
                     None => continue
                 };
             }
@@ -73,7 +98,17 @@ pub(crate) async fn run(connection: Connection, mut channel: UnboundedReceiver<S
             m = channel.recv() => {
                 trace!("got new message {m:?}");
                 if let Some(m) = m {
-                    messages.insert(m.request_id, m);
+                    remove_closed(&mut messages, &mut discarded);
+                    let request_id = m.request_id;
+                    discarded.remove(&request_id);
+                    messages.insert(request_id, m);
+                    // the streams for this request might have arrived before the request itself
+                    check_buffer(request_id, &mut messages, &mut recv_stream_buffer);
+                    if messages.get(&request_id).is_some_and(StreamRequest::is_done) {
+                        trace!("request with {request_id} is done");
+                        let request = messages.remove(&request_id).unwrap();
+                        send_response(request);
+                    }
                 } else {
                     debug!("accept_streams channel.recv returned none");
                     break;
@@ -84,10 +119,54 @@ pub(crate) async fn run(connection: Connection, mut channel: UnboundedReceiver<S
     }
 }
 
-async fn read_request_id(recv_stream: &mut RecvStream) -> Result<u32, Error> {
-    let request_id = recv_stream.read_u32().await?;
+fn send_response(request: StreamRequest) {
+    // the request might have failed in the meantime, in that case the streams are just dropped
+    if request.response_sender.send(request.response).is_err() {
+        debug!(
+            "request {} isn't waiting for its streams anymore",
+            request.request_id
+        );
+    }
+}
+
+/// Removes the requests that stopped waiting for their streams, e.g. because they were cancelled or failed.
+/// The streams they collected are dropped, the ones still missing are dropped once they arrive.
+fn remove_closed(messages: &mut HashMap<u32, StreamRequest>, discarded: &mut HashMap<u32, usize>) {
+    messages.retain(|request_id, request| {
+        if !request.response_sender.is_closed() {
+            return true;
+        }
+
+        debug!("request {request_id} isn't waiting for its streams anymore");
+        let missing = request.num_streams as usize - request.response.len();
+        if missing > 0 {
+            discarded.insert(*request_id, missing);
+        }
+        false
+    });
+}
+
+/// Returns whether the stream belongs to a removed request, dropping it stops the peer from sending on it
+fn discard_stream(request_id: u32, discarded: &mut HashMap<u32, usize>) -> bool {
+    let missing = match discarded.get_mut(&request_id) {
+        Some(missing) => missing,
+        None => return false,
+    };
+
+    trace!("dropping a late stream of request {request_id}");
+    *missing -= 1;
+    if *missing == 0 {
+        discarded.remove(&request_id);
+    }
+    true
+}
+
+async fn read_request_id(mut recv_stream: RecvStream) -> Result<(u32, RecvStream), Error> {
+    let request_id = tokio::time::timeout(REQUEST_ID_TIMEOUT, recv_stream.read_u32())
+        .await
+        .map_err(std::io::Error::from)??;
 
-    Ok(request_id)
+    Ok((request_id, recv_stream))
 }
 
 fn check_buffer(
@@ -135,3 +214,82 @@ fn handle_stream(
 
     None
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::QClientConfig;
+    use rustls::{Certificate, PrivateKey, ServerConfig};
+    use std::sync::Arc;
+    use tokio::{
+        io::AsyncWriteExt,
+        sync::{mpsc, oneshot},
+    };
+
+    async fn connect() -> (Connection, Connection) {
+        let cert = Certificate(std::fs::read("cert/dev.crt.der").unwrap());
+        let priv_key = PrivateKey(std::fs::read("cert/dev.key.der").unwrap());
+        let server_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert], priv_key)
+            .unwrap();
+        let server = quinn::Endpoint::server(
+            quinn::ServerConfig::with_crypto(Arc::new(server_config)),
+            "127.0.0.1:0".parse().unwrap(),
+        )
+        .unwrap();
+        let mut client = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        let client_config: rustls::ClientConfig = QClientConfig::dangerous_dont_verify().into();
+        client.set_default_client_config(quinn::ClientConfig::new(Arc::new(client_config)));
+
+        let connecting = client
+            .connect(server.local_addr().unwrap(), "dev.local")
+            .unwrap();
+        let (client_connection, server_connection) =
+            tokio::join!(connecting, async { server.accept().await.unwrap().await });
+        (client_connection.unwrap(), server_connection.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_stalled_stream() {
+        let (client_connection, server_connection) = connect().await;
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(run(server_connection, rx));
+
+        // only half of the request id is sent on the first stream
+        let mut stalled = client_connection.open_uni().await.unwrap();
+        stalled.write_all(&[0, 0]).await.unwrap();
+        let mut stream = client_connection.open_uni().await.unwrap();
+        stream.write_u32(7).await.unwrap();
+
+        let (response_tx, response_rx) = oneshot::channel();
+        tx.send(StreamRequest::new(1, 7, response_tx)).unwrap();
+        let streams = tokio::time::timeout(Duration::from_secs(2), response_rx)
+            .await
+            .expect("the stalled stream held up the distributor")
+            .unwrap();
+        assert_eq!(streams.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_closed_request() {
+        let (client_connection, server_connection) = connect().await;
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(run(server_connection, rx));
+
+        // the request fails before its streams arrive
+        let (response_tx, response_rx) = oneshot::channel();
+        tx.send(StreamRequest::new(2, 3, response_tx)).unwrap();
+        drop(response_rx);
+
+        for _ in 0..2 {
+            let mut stream = client_connection.open_uni().await.unwrap();
+            stream.write_u32(3).await.unwrap();
+            tokio::time::timeout(Duration::from_secs(2), stream.stopped())
+                .await
+                .expect("the stream of a closed request wasn't dropped")
+                .unwrap();
+        }
+    }
+}
@@ -1,9 +1,14 @@
-use std::fs::{self, File, Metadata};
-use std::io::Read;
-use std::path::{Path, PathBuf};
+use std::ffi::OsString;
+use std::fs::{self, File, Metadata, Permissions};
+use std::io::{Read, SeekFrom};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
 use thiserror::Error as ThisError;
-use tokio::io::{AsyncWrite, AsyncWriteExt};
-use tracing::{error, trace};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tracing::{trace, warn};
+
+use crate::message::{FileHeader, FileTrailer, Message};
+use crate::Error;
 
 #[derive(Debug, ThisError)]
 pub enum FileError {
@@ -17,6 +22,12 @@ pub enum FileError {
     OsStringConversionError,
     #[error("join error")]
     JoinError(#[from] tokio::task::JoinError),
+    #[error("path `{0}` is not a valid relative path")]
+    InvalidPath(String),
+    #[error("path `{0}` points outside of the base path")]
+    PathOutsideBasePath(String),
+    #[error("permission denied for `{0}`")]
+    PermissionDenied(String),
 }
 
 // TODO: the usage of Path/PathBuf/impl AsRef<Path> is all over the place in this module
@@ -26,9 +37,78 @@ pub enum FileError {
 // TODO: currently everything just returns a Vec<T> of sorts. This is pretty inefficient.
 // At some point all of these could be changed to streaming bytes/files/whatever is being returned
 
+const TMP_FILE_SUFFIX: &str = ".qftp-part";
+
 #[derive(Debug)]
 pub struct FileManager {
     base_path: PathBuf,
+    /// the user the files are accessed as, `None` if every file the process can access is accessible
+    access: Option<FileAccess>,
+}
+
+// the permission bits of the "other" class, the bits of the owner and group class are shifted by 6 and 3
+const READ: u32 = 0o4;
+const WRITE: u32 = 0o2;
+const EXECUTE: u32 = 0o1;
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+
+/// The owner and mode of a file, which the permissions of a [FileAccess] are checked against
+pub(crate) trait FileMode {
+    fn uid(&self) -> u32;
+    fn gid(&self) -> u32;
+    /// The `st_mode`, containing the file type and the permission bits
+    fn mode(&self) -> u32;
+}
+
+impl FileMode for Metadata {
+    fn uid(&self) -> u32 {
+        MetadataExt::uid(self)
+    }
+
+    fn gid(&self) -> u32 {
+        MetadataExt::gid(self)
+    }
+
+    fn mode(&self) -> u32 {
+        MetadataExt::mode(self)
+    }
+}
+
+/// The unix user and groups a [FileManager] accesses files as
+#[derive(Debug, Clone)]
+pub struct FileAccess {
+    uid: u32,
+    gids: Vec<u32>,
+}
+
+impl FileAccess {
+    pub fn new(uid: u32, gids: Vec<u32>) -> Self {
+        FileAccess { uid, gids }
+    }
+
+    /// Checks if the owner and mode of `file` grant all of `permissions`.
+    /// Like on unix only the class that matches first is checked: owner, then group, then other. root may access everything.
+    fn allows(&self, file: &impl FileMode, permissions: u32) -> bool {
+        if self.uid == 0 {
+            return true;
+        }
+
+        let shift = match file {
+            file if file.uid() == self.uid => 6,
+            file if self.gids.contains(&file.gid()) => 3,
+            _ => 0,
+        };
+        (file.mode() >> shift) & permissions == permissions
+    }
+
+    /// Files have to be readable, directories readable and searchable
+    fn can_read(&self, file: &impl FileMode) -> bool {
+        match file.mode() & S_IFMT {
+            S_IFDIR => self.allows(file, READ | EXECUTE),
+            _ => self.allows(file, READ),
+        }
+    }
 }
 
 // TODO: does path and relative path both need to be PathBuf?
@@ -39,6 +119,8 @@ pub struct QFile {
     pub(crate) metadata: Metadata,
     pub(crate) path: PathBuf,
     pub(crate) relative_path: PathBuf,
+    /// the byte offset to start sending the file from, used to resume a transfer
+    pub(crate) offset: u64,
     file: Option<File>,
 }
 
@@ -48,6 +130,7 @@ impl QFile {
             metadata,
             path,
             relative_path,
+            offset: 0,
             file: None,
         }
     }
@@ -59,12 +142,21 @@ impl QFile {
         Ok(self.file.as_mut().unwrap())
     }
 
-    pub async fn send<T>(&mut self, writer: &mut T) -> Result<(), FileError>
+    /// Sends the contents of the file from `offset` on.
+    /// Returns the BLAKE3 hash of the whole file, including the part before `offset`.
+    pub async fn send<T>(&mut self, writer: &mut T) -> Result<blake3::Hash, FileError>
     where
         T: AsyncWrite + Send + Sync + Unpin,
     {
-        let mut len = self.metadata.len() as usize;
+        let mut len = self.metadata.len().saturating_sub(self.offset) as usize;
+        let offset = self.offset;
         let fs_file = self.file()?;
+        let mut hasher = blake3::Hasher::new();
+
+        // the receiver already has the bytes before the offset, they are only part of the hash
+        if std::io::copy(&mut (&mut *fs_file).take(offset), &mut hasher)? != offset {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
         let mut buf = [0; 4096];
 
         while len != 0 {
@@ -72,43 +164,68 @@ impl QFile {
             match len {
                 _ if len <= 4096 => {
                     trace!("len: {len}, reading remaining bytes");
-                    fs_file.read_exact(&mut buf[0..len]).unwrap();
+                    fs_file.read_exact(&mut buf[0..len])?;
                     len -= len;
                 }
                 _ => {
                     trace!("len: {len}, reading whole buffer");
-                    fs_file.read_exact(&mut buf).unwrap();
+                    fs_file.read_exact(&mut buf)?;
                     len -= 4096;
                 }
             }
 
             read -= len;
 
+            hasher.update(&buf[0..read]);
             writer.write_all(&buf[0..read]).await?;
             trace!("Wrote {read} bytes. {len} bytes remaining");
         }
         trace!("finish writing file");
-        Ok(())
+        Ok(hasher.finalize())
     }
 }
 
 impl FileManager {
     pub fn new(base_path: impl AsRef<Path>) -> Result<Self, FileError> {
-        let mut base_path_buf = PathBuf::new();
-        base_path_buf.push(base_path);
-        base_path_buf.canonicalize()?;
+        // the base path has to be canonical, since resolved paths are checked against it
+        let base_path_buf = base_path.as_ref().canonicalize()?;
         if !base_path_buf.is_dir() {
             return Err(FileError::BasePathNotADir);
         }
 
         Ok(FileManager {
             base_path: base_path_buf,
+            access: None,
         })
     }
 
+    /// Returns a [FileManager] scoped to `root`. A relative `root` is resolved with [resolve](FileManager::resolve),
+    /// so it can't leave the base path. An absolute `root` is used as is. Both have to be an existing directory.
+    pub fn with_root(&self, root: impl AsRef<Path>) -> Result<Self, FileError> {
+        let root = root.as_ref();
+        let mut file_manager = match root.is_absolute() {
+            true => FileManager::new(root)?,
+            false => FileManager::new(self.join(root)?)?,
+        };
+        file_manager.access = self.access.clone();
+
+        Ok(file_manager)
+    }
+
+    /// Returns a [FileManager] for the same base path that only accesses the files `access` is allowed to read.
+    /// Inaccessible files are left out of [walk_dir](FileManager::walk_dir).
+    pub fn with_access(&self, access: FileAccess) -> Self {
+        FileManager {
+            base_path: self.base_path.clone(),
+            access: Some(access),
+        }
+    }
+
     fn walk_dir_impl(
         path: impl AsRef<Path>,
         offset: impl AsRef<Path> + Copy,
+        max_depth: Option<u32>,
+        access: Option<&FileAccess>,
         result: &mut Vec<QFile>,
     ) -> Result<(), FileError> {
         let dir = fs::read_dir(&path)?;
@@ -118,47 +235,352 @@ impl FileManager {
 
             let file_type = entry.file_type()?;
 
+            // symlinks are listed, but never followed
+            if !(file_type.is_dir() || file_type.is_file() || file_type.is_symlink()) {
+                continue;
+            }
+
+            // TODO: there are a lot of allocations here
+            // There is definitely a more efficient way to do this.
+            let mut relative_path = PathBuf::new();
+            relative_path.push(offset);
+            relative_path.push(entry.file_name());
+
+            let metadata = entry.metadata()?;
+            if access.is_some_and(|access| !access.can_read(&metadata)) {
+                trace!("{relative_path:?} isn't accessible, skipping it");
+                continue;
+            }
+
+            let mut full_path = PathBuf::new();
+            full_path.push(path.as_ref());
+            full_path.push(entry.file_name());
+            result.push(QFile::new(metadata, full_path, relative_path));
+
             if file_type.is_dir() {
+                let max_depth = match max_depth {
+                    Some(1) => continue,
+                    max_depth => max_depth.map(|depth| depth - 1),
+                };
                 let mut offset = offset.as_ref().to_path_buf();
-                offset.push(entry.path().iter().last().unwrap());
-
-                FileManager::walk_dir_impl(entry.path(), &offset, result)?;
-            } else if file_type.is_file() {
-                // TODO: there are a lot of allocations here
-                // There is definitely a more efficient way to do this.
-                let mut relative_path = PathBuf::new();
-                relative_path.push(offset);
-                relative_path.push(entry.file_name());
-
-                let mut full_path = PathBuf::new();
-                full_path.push(path.as_ref());
-                full_path.push(entry.file_name());
-                result.push(QFile::new(
-                    entry.metadata().unwrap(),
-                    full_path,
-                    relative_path,
+                offset.push(entry.file_name());
+
+                FileManager::walk_dir_impl(entry.path(), &offset, max_depth, access, result)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Resolves a path received from a client to a path relative to the base path.
+    ///
+    /// The path is always interpreted relative to the base path, so `/` and `` both refer to the base path itself.
+    /// `.` and `..` components are normalized, and a path that would leave the base path, either through `..`
+    /// or by following a symlink, is rejected. Every path coming from a client has to go through this function.
+    pub(crate) fn resolve(&self, path: impl AsRef<Path>) -> Result<PathBuf, FileError> {
+        let path = path.as_ref();
+        let relative_path = FileManager::normalize(path)?;
+        self.check_symlinks(&relative_path).map_err(|e| match e {
+            FileError::PathOutsideBasePath(_) => {
+                FileError::PathOutsideBasePath(path.display().to_string())
+            }
+            e => e,
+        })?;
+
+        Ok(relative_path)
+    }
+
+    /// Like [resolve](FileManager::resolve), but only the directory containing `path` has to stay inside the base path.
+    /// The last component isn't followed, so a symlink itself can be removed or renamed, even if it is dangling
+    /// or points outside of the base path. The base path itself is rejected.
+    fn resolve_entry(&self, path: impl AsRef<Path>) -> Result<PathBuf, FileError> {
+        let path = path.as_ref();
+        let relative_path = FileManager::normalize(path)?;
+        let parent = match relative_path.parent() {
+            Some(parent) => parent,
+            None => return Err(FileError::InvalidPath(path.display().to_string())),
+        };
+        self.check_symlinks(parent).map_err(|e| match e {
+            FileError::PathOutsideBasePath(_) => {
+                FileError::PathOutsideBasePath(path.display().to_string())
+            }
+            e => e,
+        })?;
+
+        Ok(relative_path)
+    }
+
+    /// Normalizes `.` and `..` components of a client path, a path leaving the base path is rejected
+    fn normalize(path: &Path) -> Result<PathBuf, FileError> {
+        let mut relative_path = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::RootDir | Component::CurDir => (),
+                Component::Normal(component) => relative_path.push(component),
+                Component::ParentDir => {
+                    if !relative_path.pop() {
+                        return Err(FileError::PathOutsideBasePath(path.display().to_string()));
+                    }
+                }
+                Component::Prefix(_) => return Err(FileError::PathIsAbsolute),
+            }
+        }
+
+        Ok(relative_path)
+    }
+
+    /// Makes sure `relative_path` doesn't leave the base path when following symlinks.
+    /// `relative_path` doesn't have to exist, in that case the longest existing part of it is checked.
+    fn check_symlinks(&self, relative_path: &Path) -> Result<(), FileError> {
+        let mut existing = self.base_path.join(relative_path);
+        loop {
+            match existing.canonicalize() {
+                Ok(canonical) if canonical.starts_with(&self.base_path) => return Ok(()),
+                Ok(_) => {
+                    return Err(FileError::PathOutsideBasePath(
+                        relative_path.display().to_string(),
+                    ))
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    // a dangling symlink could still be followed when creating a file, so don't allow them at all
+                    if existing.symlink_metadata().is_ok() {
+                        return Err(FileError::PathOutsideBasePath(
+                            relative_path.display().to_string(),
+                        ));
+                    }
+                    // the base path itself always exists, so this terminates before running out of components
+                    existing.pop();
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Makes sure `relative_path` can be read, and every directory leading to it searched, by the user of the [FileManager]
+    fn check_access(&self, relative_path: &Path) -> Result<(), FileError> {
+        let access = match &self.access {
+            Some(access) => access,
+            None => return Ok(()),
+        };
+
+        let mut path = self.base_path.clone();
+        let mut components = relative_path.components();
+        loop {
+            let metadata = fs::metadata(&path)?;
+            let component = components.next();
+            let allowed = match component {
+                Some(_) => access.allows(&metadata, EXECUTE),
+                None => access.can_read(&metadata),
+            };
+            if !allowed {
+                return Err(FileError::PermissionDenied(
+                    relative_path.display().to_string(),
+                ));
+            }
+
+            match component {
+                Some(component) => path.push(component),
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Makes sure the user of the [FileManager] may create, remove or replace `relative_path`.
+    /// Like on unix, that is decided by the directory containing it, which has to be writable and searchable,
+    /// as well as every directory leading to it. Missing directories are created in the closest existing one,
+    /// so that one is checked instead.
+    fn check_write_access(&self, relative_path: &Path) -> Result<(), FileError> {
+        let access = match &self.access {
+            Some(access) => access,
+            None => return Ok(()),
+        };
+        let denied = || FileError::PermissionDenied(relative_path.display().to_string());
+
+        let mut dir = self.base_path.clone();
+        for component in relative_path
+            .parent()
+            .into_iter()
+            .flat_map(Path::components)
+        {
+            let next = dir.join(component);
+            if !next.exists() {
+                break;
+            }
+            if !access.allows(&fs::metadata(&dir)?, EXECUTE) {
+                return Err(denied());
+            }
+            dir = next;
+        }
+        match access.allows(&fs::metadata(&dir)?, WRITE | EXECUTE) {
+            true => Ok(()),
+            false => Err(denied()),
+        }
+    }
+
+    /// Makes sure the user of the [FileManager] may remove everything inside the directory `relative_path`.
+    /// Every directory in it has to be readable, writable and searchable, like for `rm -r`.
+    fn check_tree_write_access(&self, relative_path: &Path) -> Result<(), FileError> {
+        let access = match &self.access {
+            Some(access) => access,
+            None => return Ok(()),
+        };
+
+        let mut dirs = vec![self.base_path.join(relative_path)];
+        while let Some(dir) = dirs.pop() {
+            if !access.allows(&fs::symlink_metadata(&dir)?, READ | WRITE | EXECUTE) {
+                return Err(FileError::PermissionDenied(
+                    relative_path.display().to_string(),
                 ));
             }
+            for entry in fs::read_dir(&dir)? {
+                let entry = entry?;
+                if entry.file_type()?.is_dir() {
+                    dirs.push(entry.path());
+                }
+            }
         }
 
         Ok(())
     }
 
-    pub(crate) async fn walk_dir(
+    /// Like [join](FileManager::join), but also makes sure the user of the [FileManager] may create or replace the path
+    pub(crate) fn join_writable(&self, offset: impl AsRef<Path>) -> Result<PathBuf, FileError> {
+        let relative_path = self.resolve(offset)?;
+        self.check_write_access(&relative_path)?;
+        Ok(self.base_path.join(relative_path))
+    }
+
+    /// Resolves `offset` with [resolve](FileManager::resolve) and joins it onto the base path
+    pub(crate) fn join(&self, offset: impl AsRef<Path>) -> Result<PathBuf, FileError> {
+        Ok(self.base_path.join(self.resolve(offset)?))
+    }
+
+    /// Creates the directory `path`. `path` is resolved with [resolve](FileManager::resolve).
+    pub(crate) async fn make_dir(&self, path: impl AsRef<Path>) -> Result<(), FileError> {
+        let path = self.join_writable(path)?;
+        tokio::fs::create_dir(path).await?;
+
+        Ok(())
+    }
+
+    /// Removes the directory `path`. `path` is resolved with [resolve](FileManager::resolve).
+    /// If `recursive` is false, the directory has to be empty.
+    pub(crate) async fn remove_dir(
         &self,
-        offset: impl AsRef<Path> + Send + 'static + Copy,
-    ) -> Result<Vec<QFile>, FileError> {
-        let mut base_path = self.base_path.clone();
-        if !offset.as_ref().as_os_str().is_empty() {
-            if offset.as_ref().is_absolute() {
-                return Err(FileError::PathIsAbsolute);
-            }
+        path: impl AsRef<Path>,
+        recursive: bool,
+    ) -> Result<(), FileError> {
+        let relative_path = self.non_base_path(path)?;
+        self.check_write_access(&relative_path)?;
+        if recursive {
+            self.check_tree_write_access(&relative_path)?;
+        }
+        let path = self.base_path.join(relative_path);
+        match recursive {
+            true => tokio::fs::remove_dir_all(path).await?,
+            false => tokio::fs::remove_dir(path).await?,
+        }
+
+        Ok(())
+    }
 
-            base_path.push(offset);
+    /// Removes the file or symlink `path`. Directories have to be removed with [remove_dir](FileManager::remove_dir).
+    /// `path` is resolved with [resolve_entry](FileManager::resolve_entry), so a symlink is removed instead of its target.
+    pub(crate) async fn remove_file(&self, path: impl AsRef<Path>) -> Result<(), FileError> {
+        let relative_path = self.resolve_entry(path)?;
+        self.check_write_access(&relative_path)?;
+        tokio::fs::remove_file(self.base_path.join(relative_path)).await?;
+
+        Ok(())
+    }
+
+    /// Renames `path` to `new_name`, keeping it in the same directory.
+    /// `new_name` has to be a plain file name. `path` is resolved with [resolve_entry](FileManager::resolve_entry),
+    /// so a symlink is renamed instead of its target.
+    pub(crate) async fn rename(
+        &self,
+        path: impl AsRef<Path>,
+        new_name: &str,
+    ) -> Result<(), FileError> {
+        let mut components = Path::new(new_name).components();
+        if !matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        ) {
+            return Err(FileError::InvalidPath(new_name.to_string()));
+        }
+
+        let relative_path = self.resolve_entry(path)?;
+        let destination = relative_path.with_file_name(new_name);
+        self.rename_impl(&relative_path, &destination).await
+    }
+
+    /// Moves `path` to `destination`. If `destination` is an existing directory, `path` is moved into it.
+    /// `path` is resolved with [resolve_entry](FileManager::resolve_entry), `destination` with [resolve](FileManager::resolve).
+    pub(crate) async fn move_path(
+        &self,
+        path: impl AsRef<Path>,
+        destination: impl AsRef<Path>,
+    ) -> Result<(), FileError> {
+        let relative_path = self.resolve_entry(path)?;
+        let mut destination = self.resolve(destination)?;
+        if self.base_path.join(&destination).is_dir() {
+            // resolve_entry makes sure there always is a file name
+            destination.push(relative_path.file_name().unwrap());
         }
+
+        self.rename_impl(&relative_path, &destination).await
+    }
+
+    async fn rename_impl(&self, from: &Path, to: &Path) -> Result<(), FileError> {
+        self.check_write_access(from)?;
+        // the destination has to be resolved again, it might contain a new symlink
+        let to = self.join_writable(to)?;
+        // don't silently replace existing files
+        if to.symlink_metadata().is_ok() {
+            return Err(std::io::Error::from(std::io::ErrorKind::AlreadyExists).into());
+        }
+
+        tokio::fs::rename(self.base_path.join(from), to).await?;
+
+        Ok(())
+    }
+
+    /// Resolves `path` with [resolve](FileManager::resolve), but rejects the base path itself
+    fn non_base_path(&self, path: impl AsRef<Path>) -> Result<PathBuf, FileError> {
+        let relative_path = self.resolve(path.as_ref())?;
+        if relative_path.as_os_str().is_empty() {
+            return Err(FileError::InvalidPath(path.as_ref().display().to_string()));
+        }
+
+        Ok(relative_path)
+    }
+
+    /// Returns all files, directories and symlinks below `offset`. `offset` is resolved with [resolve](FileManager::resolve).
+    /// Directories are always returned before their contents. Symlinks are returned, but not followed.
+    /// Entries the user of the [FileManager] can't read are left out, and so is everything below them.
+    ///
+    /// `max_depth` limits how many directory levels are visited. `Some(1)` only returns the entries directly in `offset`,
+    /// `None` walks the whole tree. `Some(0)` is treated the same as `Some(1)`.
+    pub(crate) async fn walk_dir(
+        &self,
+        offset: impl AsRef<Path>,
+        max_depth: Option<u32>,
+    ) -> Result<Vec<QFile>, FileError> {
+        let max_depth = max_depth.map(|depth| depth.max(1));
+        let offset = self.resolve(offset)?;
+        self.check_access(&offset)?;
+        let base_path = self.base_path.join(&offset);
+        let access = self.access.clone();
         let result: Result<Vec<QFile>, FileError> = tokio::task::spawn_blocking(move || {
             let mut result = Vec::new();
-            FileManager::walk_dir_impl(base_path, offset, &mut result)?;
+            FileManager::walk_dir_impl(
+                base_path,
+                &offset,
+                max_depth,
+                access.as_ref(),
+                &mut result,
+            )?;
 
             Ok(result)
         })
@@ -177,17 +599,331 @@ impl FileManager {
     }
 }
 
+/// Joins `relative_path` as received from the server onto `local_dir`.
+///
+/// Only plain path components are allowed, so a path can never point outside of `local_dir`.
+pub(crate) fn local_path(local_dir: &Path, relative_path: &str) -> Result<PathBuf, FileError> {
+    let path = Path::new(relative_path);
+    if path.as_os_str().is_empty()
+        || !path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(FileError::InvalidPath(relative_path.to_string()));
+    }
+
+    Ok(local_dir.join(path))
+}
+
+/// Writes the file described by `header` from `reader` to `path`.
+/// Missing parent directories are created and the mode and modification time of the file are restored.
+///
+/// The contents are first written to a temporary file next to `path` which is renamed to `path` once
+/// the whole file has been received, so nobody ever sees a partially written file at `path`.
+/// If the transfer fails, the temporary file is kept so the transfer can be resumed from there.
+/// When the [FileHeader] has an offset, the contents are appended to the temporary file at that offset.
+pub(crate) async fn recv_file<T>(
+    reader: &mut T,
+    path: &Path,
+    header: &FileHeader,
+) -> Result<(), Error>
+where
+    T: AsyncRead + Send + Sync + Unpin,
+{
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+
+    let tmp_path = tmp_path(path)?;
+    trace!("writing {} bytes to {tmp_path:?}", header.len());
+    match recv_file_impl(reader, &tmp_path, header).await {
+        Ok(()) => {
+            trace!("renaming {tmp_path:?} to {path:?}");
+            tokio::fs::rename(&tmp_path, path).await?;
+            Ok(())
+        }
+        // the partial file is corrupted, resuming from it would only fail again
+        Err(e @ Error::ChecksumMismatch(_)) => {
+            if let Err(e) = tokio::fs::remove_file(&tmp_path).await {
+                warn!("failed to remove corrupted file {tmp_path:?}: {e}");
+            }
+            Err(e)
+        }
+        Err(e) => {
+            // the modification time of the partial file is set to the one of the file being received,
+            // a resumed transfer uses it to check that the file didn't change in the meantime
+            let set_modified = std::fs::File::options()
+                .write(true)
+                .open(&tmp_path)
+                .and_then(|file| file.set_modified(header.modified()));
+            if let Err(e) = set_modified {
+                warn!("failed to set the modification time of partial file {tmp_path:?}: {e}");
+            }
+            Err(e)
+        }
+    }
+}
+
+async fn recv_file_impl<T>(reader: &mut T, path: &Path, header: &FileHeader) -> Result<(), Error>
+where
+    T: AsyncRead + Send + Sync + Unpin,
+{
+    let offset = header.offset();
+    let len = header.len().checked_sub(offset).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "the offset is larger than the file",
+        )
+    })?;
+
+    let mut hasher = blake3::Hasher::new();
+    let mut file = match offset {
+        0 => tokio::fs::File::create(path).await?,
+        offset => {
+            let mut file = tokio::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(path)
+                .await?;
+            if file.metadata().await?.len() < offset {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "the partial file is shorter than the offset",
+                )
+                .into());
+            }
+            file.set_len(offset).await?;
+            // the checksum covers the already received bytes as well
+            if copy_hashed(&mut file, &mut tokio::io::sink(), offset, &mut hasher).await? != offset
+            {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+            file.seek(SeekFrom::Start(offset)).await?;
+            file
+        }
+    };
+    if copy_hashed(reader, &mut file, len, &mut hasher).await? != len {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    file.flush().await?;
+
+    let trailer = FileTrailer::recv(reader).await?;
+    if hasher.finalize().as_bytes() != trailer.hash() {
+        return Err(Error::ChecksumMismatch(header.path().to_string()));
+    }
+
+    // only the permission bits are restored, setuid/setgid and sticky bits are dropped
+    file.set_permissions(Permissions::from_mode(header.mode() & 0o777))
+        .await?;
+    let file = file.into_std().await;
+    file.set_modified(header.modified())?;
+    file.sync_all()?;
+
+    Ok(())
+}
+
+/// Copies up to `len` bytes from `reader` to `writer` and adds them to `hasher`.
+/// Returns the number of bytes copied, which is less than `len` if `reader` reached its end.
+async fn copy_hashed<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    len: u64,
+    hasher: &mut blake3::Hasher,
+) -> std::io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = [0; 4096];
+    let mut copied = 0;
+    while copied < len {
+        let remaining = (len - copied).min(buf.len() as u64) as usize;
+        let read = reader.read(&mut buf[..remaining]).await?;
+        if read == 0 {
+            break;
+        }
+
+        hasher.update(&buf[..read]);
+        writer.write_all(&buf[..read]).await?;
+        copied += read as u64;
+    }
+
+    Ok(copied)
+}
+
+/// If `path` is the temporary file of a partially received file, returns the path of the file being received
+pub(crate) fn partial_file_target(path: &Path) -> Option<PathBuf> {
+    let file_name = path.file_name()?.to_str()?;
+    let target_name = file_name
+        .strip_prefix('.')?
+        .strip_suffix(TMP_FILE_SUFFIX)
+        .filter(|name| !name.is_empty())?;
+
+    Some(path.with_file_name(target_name))
+}
+
+/// The path of the temporary file used while receiving the file at `path`
+fn tmp_path(path: &Path) -> Result<PathBuf, FileError> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| FileError::InvalidPath(path.display().to_string()))?;
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(file_name);
+    tmp_name.push(TMP_FILE_SUFFIX);
+
+    Ok(path.with_file_name(tmp_name))
+}
+
 #[cfg(test)]
 mod test {
-    use super::FileManager;
+    use super::{FileAccess, FileError, FileManager, FileMode};
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use std::path::{Path, PathBuf};
+
+    struct CraftedMetadata {
+        uid: u32,
+        gid: u32,
+        mode: u32,
+    }
+
+    impl FileMode for CraftedMetadata {
+        fn uid(&self) -> u32 {
+            self.uid
+        }
+
+        fn gid(&self) -> u32 {
+            self.gid
+        }
+
+        fn mode(&self) -> u32 {
+            self.mode
+        }
+    }
+
+    #[test]
+    fn test_file_access() {
+        let file = |uid, gid, mode: u32| CraftedMetadata {
+            uid,
+            gid,
+            mode: 0o100000 | mode,
+        };
+        let dir = |uid, gid, mode: u32| CraftedMetadata {
+            uid,
+            gid,
+            mode: 0o040000 | mode,
+        };
+        let access = FileAccess::new(1000, vec![100, 200]);
+
+        assert!(access.can_read(&file(1000, 0, 0o400)));
+        assert!(access.can_read(&file(0, 200, 0o040)));
+        assert!(access.can_read(&file(0, 0, 0o004)));
+        assert!(!access.can_read(&file(0, 0, 0o660)));
+        // only the first matching class counts, the owner can't read through the group or other bits
+        assert!(!access.can_read(&file(1000, 100, 0o044)));
+        assert!(!access.can_read(&file(0, 100, 0o404)));
+
+        // directories have to be searchable too
+        assert!(access.can_read(&dir(1000, 0, 0o500)));
+        assert!(!access.can_read(&dir(1000, 0, 0o400)));
+        assert!(!access.can_read(&dir(0, 0, 0o004)));
+        assert!(access.allows(&dir(0, 0, 0o001), super::EXECUTE));
+
+        let root = FileAccess::new(0, Vec::new());
+        assert!(root.can_read(&file(1000, 1000, 0o000)));
+        assert!(root.can_read(&dir(1000, 1000, 0o000)));
+    }
+
+    #[tokio::test]
+    async fn test_walk_dir_access() {
+        let base = std::env::temp_dir().join("qftp_test_walk_dir_access");
+        let _ = std::fs::remove_dir_all(&base);
+        std::fs::create_dir_all(base.join("public/unsearchable")).unwrap();
+        std::fs::create_dir_all(base.join("private")).unwrap();
+        std::fs::write(base.join("public/file"), "content").unwrap();
+        std::fs::write(base.join("public/private_file"), "content").unwrap();
+        std::fs::write(base.join("public/unsearchable/file"), "content").unwrap();
+        std::fs::write(base.join("private/file"), "content").unwrap();
+        let set_mode = |path: &str, mode| {
+            std::fs::set_permissions(base.join(path), std::fs::Permissions::from_mode(mode))
+                .unwrap()
+        };
+        set_mode("", 0o755);
+        set_mode("public", 0o755);
+        set_mode("public/file", 0o644);
+        set_mode("public/private_file", 0o600);
+        set_mode("public/unsearchable", 0o744);
+        set_mode("private", 0o700);
+
+        // a user that neither owns the files nor is in their group
+        let metadata = std::fs::metadata(&base).unwrap();
+        let access = FileAccess::new(
+            MetadataExt::uid(&metadata) + 1,
+            vec![MetadataExt::gid(&metadata) + 1],
+        );
+        let f = FileManager::new(&base).expect("expect creating a file manager not to fail");
+        let user_f = f.with_access(access);
+
+        let mut files: Vec<PathBuf> = user_f
+            .walk_dir("", None)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|file| file.relative_path)
+            .collect();
+        files.sort();
+        assert_eq!(files, [Path::new("public"), Path::new("public/file")]);
+        for path in ["private", "private/file", "public/unsearchable"] {
+            assert!(
+                matches!(
+                    user_f.walk_dir(path, None).await,
+                    Err(FileError::PermissionDenied(_))
+                ),
+                "{path} should be rejected"
+            );
+        }
+
+        // changes need a writable directory, even for files that can't be listed
+        fn is_denied<T>(result: Result<T, FileError>) -> bool {
+            matches!(result, Err(FileError::PermissionDenied(_)))
+        }
+        assert!(is_denied(user_f.join_writable("private/file")));
+        assert!(is_denied(user_f.join_writable("private/new_dir/file")));
+        assert!(is_denied(user_f.join_writable("public/new_file")));
+        assert!(is_denied(user_f.make_dir("public/new_dir").await));
+        assert!(is_denied(user_f.remove_file("private/file").await));
+        assert!(is_denied(user_f.remove_file("public/file").await));
+        assert!(is_denied(user_f.remove_dir("private", true).await));
+        assert!(is_denied(user_f.rename("private/file", "renamed").await));
+        assert!(is_denied(user_f.move_path("public/file", "private").await));
+
+        set_mode("", 0o757);
+        set_mode("public", 0o757);
+        assert!(is_denied(user_f.remove_dir("private", true).await));
+        assert!(is_denied(user_f.move_path("public/file", "private").await));
+        // the writable base path can't be used to reach into the directory below it
+        assert!(is_denied(user_f.join_writable("private/file")));
+        user_f.make_dir("public/new_dir").await.unwrap();
+        assert!(user_f.join_writable("new_dir/nested/file").is_ok());
+        user_f.rename("public/file", "renamed").await.unwrap();
+        user_f.move_path("public/renamed", "").await.unwrap();
+        user_f.remove_file("renamed").await.unwrap();
+        user_f.remove_dir("public/new_dir", false).await.unwrap();
+
+        // without a user everything is accessible
+        std::fs::write(base.join("public/file"), "content").unwrap();
+        assert_eq!(f.walk_dir("", None).await.unwrap().len(), 7);
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
     #[tokio::test]
     async fn test_walk_dir() {
         let path = format!("{}/tests/walk_dir", env!("CARGO_MANIFEST_DIR"));
         println!("{path}");
         let f = FileManager::new(path).expect("expect creating a file manager not to fail");
-        let result = f.walk_dir("").await.unwrap();
+        let result = f.walk_dir("", None).await.unwrap();
         println!("{result:#?}");
-        assert_eq!(result.len(), 4)
+        assert_eq!(result.len(), 7);
+        assert_eq!(result.iter().filter(|f| f.metadata.is_dir()).count(), 3)
     }
 
     #[tokio::test]
@@ -195,8 +931,183 @@ mod test {
         let path = format!("{}/tests/walk_dir", env!("CARGO_MANIFEST_DIR"));
         println!("{path}");
         let f = FileManager::new(path).expect("expect creating a file manager not to fail");
-        let result = f.walk_dir("b").await.unwrap();
+        let result = f.walk_dir("b", None).await.unwrap();
+
+        assert_eq!(result.len(), 3)
+    }
+
+    #[tokio::test]
+    async fn test_walk_dir_max_depth() {
+        let path = format!("{}/tests/walk_dir", env!("CARGO_MANIFEST_DIR"));
+        let f = FileManager::new(path).expect("expect creating a file manager not to fail");
+        let result = f.walk_dir("", Some(1)).await.unwrap();
+        assert_eq!(result.len(), 3);
+
+        let result = f.walk_dir("", Some(2)).await.unwrap();
+        assert_eq!(result.len(), 6);
+
+        let result = f.walk_dir("b", Some(1)).await.unwrap();
+        assert_eq!(result.len(), 2);
+    }
+
+    #[test]
+    fn test_resolve() {
+        let path = format!("{}/tests/walk_dir", env!("CARGO_MANIFEST_DIR"));
+        let f = FileManager::new(path).expect("expect creating a file manager not to fail");
+
+        assert_eq!(f.resolve("").unwrap(), PathBuf::new());
+        assert_eq!(f.resolve("/").unwrap(), PathBuf::new());
+        assert_eq!(f.resolve("/b/./c").unwrap(), Path::new("b/c"));
+        assert_eq!(f.resolve("b/../b/c/..").unwrap(), Path::new("b"));
+        assert_eq!(
+            f.resolve("does/not/exist").unwrap(),
+            Path::new("does/not/exist")
+        );
+
+        for path in [
+            "..",
+            "/..",
+            "../walk_dir",
+            "b/../..",
+            "b/c/../../../etc/passwd",
+        ] {
+            assert!(
+                matches!(f.resolve(path), Err(FileError::PathOutsideBasePath(_))),
+                "{path} should be rejected"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resolve_symlinks() {
+        let outside = std::env::temp_dir().join("qftp_test_resolve_symlinks_outside");
+        let base = std::env::temp_dir().join("qftp_test_resolve_symlinks");
+        let _ = std::fs::remove_dir_all(&outside);
+        let _ = std::fs::remove_dir_all(&base);
+        std::fs::create_dir_all(outside.join("dir")).unwrap();
+        std::fs::create_dir_all(base.join("inside")).unwrap();
+        std::os::unix::fs::symlink(&outside, base.join("escape")).unwrap();
+        std::os::unix::fs::symlink(base.join("inside"), base.join("internal")).unwrap();
+        std::os::unix::fs::symlink(outside.join("missing"), base.join("dangling")).unwrap();
+
+        let f = FileManager::new(&base).expect("expect creating a file manager not to fail");
+        for path in [
+            "escape",
+            "escape/dir",
+            "escape/dir/new_file",
+            "dangling",
+            "inside/../escape",
+        ] {
+            assert!(
+                matches!(f.resolve(path), Err(FileError::PathOutsideBasePath(_))),
+                "{path} should be rejected"
+            );
+        }
+        assert!(f.join("escape/new_file").is_err());
+        assert!(f.walk_dir("escape", None).await.is_err());
+
+        assert_eq!(f.resolve("internal").unwrap(), Path::new("internal"));
+        assert_eq!(
+            f.resolve("internal/new_file").unwrap(),
+            Path::new("internal/new_file")
+        );
+
+        std::fs::remove_dir_all(&outside).unwrap();
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_with_root() {
+        let path = format!("{}/tests/walk_dir", env!("CARGO_MANIFEST_DIR"));
+        let f = FileManager::new(&path).expect("expect creating a file manager not to fail");
+
+        let root_f = f.with_root("b").unwrap();
+        assert_eq!(root_f.walk_dir("", None).await.unwrap().len(), 3);
+        assert_eq!(root_f.walk_dir("/c", None).await.unwrap().len(), 1);
+        assert!(matches!(
+            root_f.resolve("../a"),
+            Err(FileError::PathOutsideBasePath(_))
+        ));
+
+        let root_f = f.with_root(format!("{path}/b/c")).unwrap();
+        assert_eq!(root_f.walk_dir("", None).await.unwrap().len(), 1);
+
+        assert!(matches!(
+            f.with_root("../walk_dir"),
+            Err(FileError::PathOutsideBasePath(_))
+        ));
+        assert!(matches!(f.with_root("missing"), Err(FileError::IOError(_))));
+        assert!(matches!(
+            f.with_root("root.txt"),
+            Err(FileError::BasePathNotADir)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_rename_and_move() {
+        let base = std::env::temp_dir().join("qftp_test_rename_and_move");
+        let _ = std::fs::remove_dir_all(&base);
+        std::fs::create_dir_all(base.join("dir")).unwrap();
+        std::fs::write(base.join("file"), "content").unwrap();
+        std::fs::write(base.join("other"), "content").unwrap();
+
+        let f = FileManager::new(&base).expect("expect creating a file manager not to fail");
+        for new_name in ["", ".", "..", "dir/file", "/file"] {
+            assert!(f.rename("file", new_name).await.is_err(), "{new_name}");
+        }
+        assert!(f.rename("file", "other").await.is_err());
+        assert!(f.rename("/", "new_base").await.is_err());
+        assert!(f.move_path("file", "../file").await.is_err());
+        assert!(f.move_path("/", "dir").await.is_err());
+
+        f.rename("file", "renamed").await.unwrap();
+        assert!(base.join("renamed").is_file());
+        f.move_path("renamed", "dir").await.unwrap();
+        assert!(base.join("dir/renamed").is_file());
+        f.move_path("dir/renamed", "moved").await.unwrap();
+        assert!(base.join("moved").is_file());
+        f.remove_file("moved").await.unwrap();
+        assert!(!base.join("moved").exists());
+        assert!(f.remove_file("dir").await.is_err());
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_symlink_operations() {
+        let outside = std::env::temp_dir().join("qftp_test_symlink_operations_outside");
+        let base = std::env::temp_dir().join("qftp_test_symlink_operations");
+        let _ = std::fs::remove_dir_all(&outside);
+        let _ = std::fs::remove_dir_all(&base);
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::create_dir_all(base.join("dir")).unwrap();
+        std::fs::write(outside.join("file"), "content").unwrap();
+        std::os::unix::fs::symlink(outside.join("file"), base.join("escape")).unwrap();
+        std::os::unix::fs::symlink(outside.join("missing"), base.join("dangling")).unwrap();
+        std::os::unix::fs::symlink(&outside, base.join("escape_dir")).unwrap();
+
+        let f = FileManager::new(&base).expect("expect creating a file manager not to fail");
+        // the links themselves are changed, their targets are left alone
+        f.rename("dangling", "renamed").await.unwrap();
+        assert!(base.join("renamed").symlink_metadata().is_ok());
+        f.move_path("renamed", "dir").await.unwrap();
+        assert!(base.join("dir/renamed").symlink_metadata().is_ok());
+        f.remove_file("dir/renamed").await.unwrap();
+        assert!(base.join("dir/renamed").symlink_metadata().is_err());
+        f.remove_file("escape").await.unwrap();
+        assert!(base.join("escape").symlink_metadata().is_err());
+        assert!(outside.join("file").is_file());
+
+        // the directory containing the link still has to be inside the base path
+        assert!(matches!(
+            f.remove_file("escape_dir/file").await,
+            Err(FileError::PathOutsideBasePath(_))
+        ));
+        assert!(f.move_path("escape_dir/file", "dir").await.is_err());
+        assert!(f.move_path("escape_dir", "escape_dir/moved").await.is_err());
+        assert!(outside.join("file").is_file());
 
-        assert_eq!(result.len(), 2)
+        std::fs::remove_dir_all(&outside).unwrap();
+        std::fs::remove_dir_all(&base).unwrap();
     }
 }
@@ -7,10 +7,12 @@ mod client;
 pub use client::{Client, ClientBuilder, QClientConfig};
 pub mod connected_client;
 mod control_stream;
+pub mod credentials;
 mod distributor;
 pub mod files;
 pub mod message;
 mod server;
+mod transfer;
 pub use control_stream::ControlStream;
 pub use server::{Server, ServerBuilder};
 
@@ -28,18 +30,65 @@ pub enum Error {
     WriteError(#[from] quinn::WriteError),
     #[error("serde_json error")]
     SerdeJsonError(#[from] serde_json::Error),
+    #[cfg(feature = "sqlite")]
+    #[error("SQLite error")]
+    SqliteError(#[from] rusqlite::Error),
     #[error("Authentication error")]
     AuthenticationError(#[from] crate::auth::AuthError),
     #[error("Version negotiation failed")]
     NegotiationError,
     #[error("Unknown MessageID `{0}`")]
     MessageIDError(u16),
-    #[error("The server didn't accept the credentials")]
-    LoginError,
+    #[error("The server didn't accept the credentials: {0}")]
+    LoginError(String),
+    #[error("failed to get the credentials: {0}")]
+    CredentialError(String),
     #[error("file error")]
     FileError(#[from] crate::files::FileError),
     #[error("error sending message from request to channel distributor")]
     RequestDistributorChannelSendError,
     #[error("error")]
     RecvErrorOneshot(#[from] tokio::sync::oneshot::error::RecvError),
+    #[error("requested {0} streams, which is more than allowed")]
+    TooManyStreams(u32),
+    #[error("the server failed to handle the request ({0}): {1}")]
+    RequestFailed(crate::message::ErrorCode, String),
+    #[error("the checksum of `{0}` doesn't match, the file was corrupted during the transfer")]
+    ChecksumMismatch(String),
+    #[error("the request was cancelled")]
+    Cancelled,
+    #[error("sent {0} resume files, which is more than allowed")]
+    TooManyResumeFiles(u32),
+}
+
+/// Application error codes a qftp connection is closed with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum CloseCode {
+    /// The connection was closed normally
+    Ok = 0,
+    /// The client and the server don't support a common protocol version
+    VersionMismatch = 1,
+    /// The client failed to log in
+    LoginFailed = 2,
+}
+
+impl From<CloseCode> for quinn::VarInt {
+    fn from(value: CloseCode) -> Self {
+        quinn::VarInt::from_u32(value as u32)
+    }
+}
+
+/// Application error codes a stream is reset or stopped with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum StreamCode {
+    /// The request the stream belongs to was cancelled
+    Cancelled = 1,
+}
+
+impl From<StreamCode> for quinn::VarInt {
+    fn from(value: StreamCode) -> Self {
+        quinn::VarInt::from_u32(value as u32)
+    }
 }
@@ -1,6 +1,6 @@
 use std::{
     fmt::{self, Debug},
-    fs::Metadata,
+    fs::{self, Metadata},
     os::unix::fs::MetadataExt,
     time::{Duration, SystemTime},
 };
@@ -8,7 +8,10 @@ use std::{
 use qftp_derive::Message;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-use crate::{files::QFile, Error};
+use crate::{
+    files::{FileError, QFile},
+    Error,
+};
 
 #[async_trait::async_trait]
 pub trait Message: Debug + Send {
@@ -31,19 +34,43 @@ pub trait Message: Debug + Send {
 }
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub(crate) enum Request {
     ListFileRequest(ListFilesRequest),
-    GetFilesRequest(GetFilesRequest),
+    GetFilesRequest(GetFilesRequest, Vec<ResumeFile>),
+    /// A [GetFilesRequest] followed by more than [MAX_RESUME_FILES] resume files, none of which were read
+    TooManyResumeFiles(GetFilesRequest),
+    PutFilesRequest(PutFilesRequest),
+    MakeDirRequest(MakeDirRequest),
+    RemoveDirRequest(RemoveDirRequest),
+    RemoveRequest(RemoveRequest),
+    RenameRequest(RenameRequest),
+    MoveRequest(MoveRequest),
+    CancelRequest(CancelRequest),
 }
 
 impl Request {
-    pub(crate) async fn next_request<T>(reader: &mut T) -> Result<Self, Error>
+    /// Reads the next request. Returns `None` if the stream ended before a new request started
+    pub(crate) async fn next_request<T>(reader: &mut T) -> Result<Option<Self>, Error>
     where
         Self: Sized,
         T: Sync + Send + Unpin + AsyncRead,
     {
-        let request_id = reader.read_u16().await?;
-        match request_id {
+        let mut request_type = [0; 2];
+        if reader.read(&mut request_type[..1]).await? == 0 {
+            return Ok(None);
+        }
+        reader.read_exact(&mut request_type[1..]).await?;
+        Ok(Some(
+            Request::recv_request(reader, u16::from_be_bytes(request_type)).await?,
+        ))
+    }
+
+    async fn recv_request<T>(reader: &mut T, request_type: u16) -> Result<Self, Error>
+    where
+        T: Sync + Send + Unpin + AsyncRead,
+    {
+        match request_type {
             0x01 => {
                 let request = ListFilesRequest::recv(reader).await?;
 
@@ -51,14 +78,110 @@ impl Request {
             }
             0x02 => {
                 let request = GetFilesRequest::recv(reader).await?;
+                if request.num_resume_files() > MAX_RESUME_FILES {
+                    return Ok(Self::TooManyResumeFiles(request));
+                }
+                let mut resume_files = Vec::new();
+                for _ in 0..request.num_resume_files() {
+                    resume_files.push(ResumeFile::recv(reader).await?);
+                }
+
+                Ok(Self::GetFilesRequest(request, resume_files))
+            }
+            0x03 => {
+                let request = PutFilesRequest::recv(reader).await?;
+
+                Ok(Self::PutFilesRequest(request))
+            }
+            0x04 => {
+                let request = MakeDirRequest::recv(reader).await?;
+
+                Ok(Self::MakeDirRequest(request))
+            }
+            0x05 => {
+                let request = RemoveDirRequest::recv(reader).await?;
+
+                Ok(Self::RemoveDirRequest(request))
+            }
+            0x06 => {
+                let request = RemoveRequest::recv(reader).await?;
 
-                Ok(Self::GetFilesRequest(request))
+                Ok(Self::RemoveRequest(request))
+            }
+            0x07 => {
+                let request = RenameRequest::recv(reader).await?;
+
+                Ok(Self::RenameRequest(request))
+            }
+            0x08 => {
+                let request = MoveRequest::recv(reader).await?;
+
+                Ok(Self::MoveRequest(request))
+            }
+            0x09 => {
+                let request = CancelRequest::recv(reader).await?;
+
+                Ok(Self::CancelRequest(request))
             }
             id => Err(Error::MessageIDError(id)),
         }
     }
 }
 
+/// The answer to a [Request], sent by the server on the control stream.
+/// Every response starts with a 1 byte message id.
+#[derive(Debug)]
+pub(crate) enum Response {
+    ErrorResponse(ErrorResponse),
+    StatusResponse(StatusResponse),
+}
+
+impl Response {
+    /// The response to the request `request_id`, which finished with `result`
+    pub(crate) fn new(request_id: u32, result: &Result<(), Error>) -> Self {
+        match result {
+            Ok(()) => Self::StatusResponse(StatusResponse::new(request_id)),
+            Err(e) => Self::ErrorResponse(ErrorResponse::new(request_id, e)),
+        }
+    }
+
+    pub(crate) async fn next_response<T>(reader: &mut T) -> Result<Self, Error>
+    where
+        T: Sync + Send + Unpin + AsyncRead,
+    {
+        let message_id = reader.read_u8().await?;
+        match message_id {
+            0x02 => Ok(Self::ErrorResponse(ErrorResponse::recv(reader).await?)),
+            0x03 => Ok(Self::StatusResponse(StatusResponse::recv(reader).await?)),
+            id => Err(Error::MessageIDError(id.into())),
+        }
+    }
+
+    pub(crate) fn request_id(&self) -> u32 {
+        match self {
+            Self::ErrorResponse(response) => response.request_id(),
+            Self::StatusResponse(response) => response.request_id(),
+        }
+    }
+
+    pub(crate) async fn send<T>(self, writer: &mut T) -> Result<(), Error>
+    where
+        T: Sync + Send + Unpin + AsyncWrite,
+    {
+        // the message id and the message are written at once, since other requests share the control stream
+        let (message_id, bytes) = match self {
+            Self::ErrorResponse(response) => (0x02, response.to_bytes()),
+            Self::StatusResponse(response) => (0x03, response.to_bytes()),
+        };
+        let mut buf = Vec::with_capacity(1 + bytes.len());
+        buf.push(message_id);
+        buf.extend(bytes);
+        writer.write_all(&buf).await?;
+
+        Ok(())
+    }
+}
+
 #[derive(Message, Debug)]
 pub struct Version {
     len: u8,
@@ -91,6 +214,42 @@ impl VersionResponse {
     }
 }
 
+/// The answer of the server to the [Version] message of the client.
+/// It starts with a 1 byte message id, the server either picked a version or rejects the client.
+#[derive(Debug)]
+pub(crate) enum VersionNegotiation {
+    VersionResponse(VersionResponse),
+    ErrorResponse(ErrorResponse),
+}
+
+#[async_trait::async_trait]
+impl Message for VersionNegotiation {
+    async fn recv<T>(reader: &mut T) -> Result<Self, Error>
+    where
+        Self: Sized,
+        T: Sync + Send + Unpin + AsyncRead,
+    {
+        let message_id = reader.read_u8().await?;
+        match message_id {
+            0x01 => Ok(Self::VersionResponse(VersionResponse::recv(reader).await?)),
+            0x02 => Ok(Self::ErrorResponse(ErrorResponse::recv(reader).await?)),
+            id => Err(Error::MessageIDError(id.into())),
+        }
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let (message_id, bytes) = match self {
+            Self::VersionResponse(response) => (0x01, response.to_bytes()),
+            Self::ErrorResponse(response) => (0x02, response.to_bytes()),
+        };
+        let mut v = Vec::with_capacity(1 + bytes.len());
+        v.push(message_id);
+        v.extend(bytes);
+
+        v
+    }
+}
+
 #[derive(Message)]
 pub struct LoginRequest {
     name_length: u8,
@@ -141,6 +300,8 @@ impl LoginRequest {
 #[derive(Message, Debug)]
 pub struct LoginResponse {
     status: u8,
+    reason_len: u32,
+    reason: String,
 }
 
 impl LoginResponse {
@@ -148,11 +309,192 @@ impl LoginResponse {
         self.status != 0
     }
 
+    /// Why the login failed. Empty if it succeeded
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+
     pub fn new(is_ok: bool) -> Self {
         LoginResponse {
             status: is_ok as u8,
+            reason_len: 0,
+            reason: String::new(),
+        }
+    }
+
+    /// Create a LoginResponse rejecting the login because of `reason`
+    pub fn failed(reason: String) -> Self {
+        LoginResponse {
+            status: 0,
+            reason_len: reason.len() as u32,
+            reason,
+        }
+    }
+}
+
+/// A way for the client to log in, see [LoginMethods]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum LoginMethod {
+    /// The client sends a [LoginRequest]
+    Password = 1,
+    /// The client sends a [PublicKeyLoginRequest] and signs the [LoginChallenge] of the server
+    PublicKey = 2,
+    /// The client is logged in with the certificate it presented during the TLS handshake, nothing else is sent.
+    /// Only picked if the server verified a client certificate, no matter which methods it's configured with
+    Certificate = 3,
+}
+
+impl TryFrom<u8> for LoginMethod {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(LoginMethod::Password),
+            2 => Ok(LoginMethod::PublicKey),
+            3 => Ok(LoginMethod::Certificate),
+            value => Err(value),
+        }
+    }
+}
+
+impl fmt::Display for LoginMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            LoginMethod::Password => "password",
+            LoginMethod::PublicKey => "public key",
+            LoginMethod::Certificate => "certificate",
+        };
+
+        write!(f, "{name}")
+    }
+}
+
+/// The login methods the client can use, in the order it prefers them
+#[derive(Message, Debug)]
+pub struct LoginMethods {
+    len: u8,
+    methods: Vec<u8>,
+}
+
+impl LoginMethods {
+    pub fn new(methods: &[LoginMethod]) -> Self {
+        LoginMethods {
+            len: methods.len() as u8,
+            methods: methods.iter().map(|method| *method as u8).collect(),
         }
     }
+
+    /// The methods in the order the client prefers them, unknown methods are skipped
+    pub fn methods(&self) -> impl Iterator<Item = LoginMethod> + '_ {
+        self.methods
+            .iter()
+            .filter_map(|method| LoginMethod::try_from(*method).ok())
+    }
+}
+
+/// The method the server picked from the [LoginMethods] of the client
+#[derive(Message, Debug)]
+pub struct LoginMethodResponse {
+    // 0 means that the server doesn't support any of the methods
+    method: u8,
+}
+
+impl LoginMethodResponse {
+    pub fn new(method: Option<LoginMethod>) -> Self {
+        LoginMethodResponse {
+            method: method.map_or(0, |method| method as u8),
+        }
+    }
+
+    pub fn method(&self) -> Option<LoginMethod> {
+        LoginMethod::try_from(self.method).ok()
+    }
+}
+
+/// Starts a [LoginMethod::PublicKey] login with an Ed25519 public key
+#[derive(Message, Debug)]
+pub struct PublicKeyLoginRequest {
+    name_length: u8,
+    name: String,
+    public_key_length: u8,
+    public_key: Vec<u8>,
+}
+
+impl PublicKeyLoginRequest {
+    /// # Panic
+    /// This function panics if the length of name or public_key is longer than u8::MAX
+    pub fn new(name: String, public_key: Vec<u8>) -> Self {
+        if name.len() > u8::MAX.into() || public_key.len() > u8::MAX.into() {
+            panic!("`name` or `public_key` are longer than {}", u8::MAX);
+        }
+        PublicKeyLoginRequest {
+            name_length: name.len() as u8,
+            name,
+            public_key_length: public_key.len() as u8,
+            public_key,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+}
+
+/// Random bytes the client has to sign to prove that it owns the private key of a [PublicKeyLoginRequest]
+#[derive(Message, Debug)]
+pub struct LoginChallenge {
+    challenge_length: u8,
+    challenge: Vec<u8>,
+}
+
+impl LoginChallenge {
+    pub fn new(challenge: Vec<u8>) -> Self {
+        LoginChallenge {
+            challenge_length: challenge.len() as u8,
+            challenge,
+        }
+    }
+
+    /// The data the client signs. Besides the challenge it contains keying material exported from the TLS
+    /// session, so a signature can't be relayed to a different connection.
+    pub(crate) fn signed_data(&self, connection: &quinn::Connection) -> Result<Vec<u8>, Error> {
+        let mut keying_material = [0; 32];
+        connection
+            .export_keying_material(&mut keying_material, b"EXPORTER-qftp-public-key-login", &[])
+            .map_err(|_| {
+                rustls::Error::General("failed to export the keying material".to_string())
+            })?;
+
+        let mut data = b"qftp public key login\0".to_vec();
+        data.extend_from_slice(&keying_material);
+        data.extend_from_slice(&self.challenge);
+        Ok(data)
+    }
+}
+
+/// The signature of the client over the [signed data](LoginChallenge::signed_data) of the [LoginChallenge]
+#[derive(Message, Debug)]
+pub struct LoginSignature {
+    signature_length: u8,
+    signature: Vec<u8>,
+}
+
+impl LoginSignature {
+    pub fn new(signature: Vec<u8>) -> Self {
+        LoginSignature {
+            signature_length: signature.len() as u8,
+            signature,
+        }
+    }
+
+    pub fn signature(&self) -> &[u8] {
+        &self.signature
+    }
 }
 
 #[derive(Debug, Message)]
@@ -160,14 +502,18 @@ pub struct ListFilesRequest {
     path_len: u32,
     path: String,
     request_id: u32,
+    // 0 means there is no limit
+    max_depth: u32,
 }
 
 impl ListFilesRequest {
-    pub(crate) fn new(path: String) -> ListFilesRequest {
+    pub(crate) fn new(request_id: u32, path: String, max_depth: Option<u32>) -> ListFilesRequest {
         ListFilesRequest {
             path_len: path.len() as u32,
             path,
-            request_id: 1325,
+            request_id,
+            // 0 would mean no limit, `Some(0)` lists the same as `Some(1)` instead
+            max_depth: max_depth.map(|depth| depth.max(1)).unwrap_or(0),
         }
     }
 
@@ -175,6 +521,14 @@ impl ListFilesRequest {
         &self.path
     }
 
+    /// How many directory levels should be listed. `None` if the whole tree should be listed
+    pub fn max_depth(&self) -> Option<u32> {
+        match self.max_depth {
+            0 => None,
+            depth => Some(depth),
+        }
+    }
+
     pub fn request_id(&self) -> u32 {
         self.request_id
     }
@@ -191,6 +545,55 @@ impl ListFileResponseHeader {
     }
 }
 
+/// The type of an entry in a [ListFileResponse] or a [FileHeader]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FileType {
+    File = 0,
+    Dir = 1,
+    Symlink = 2,
+    /// Anything else, like sockets or devices
+    Other = 255,
+}
+
+impl From<fs::FileType> for FileType {
+    fn from(value: fs::FileType) -> Self {
+        if value.is_file() {
+            FileType::File
+        } else if value.is_dir() {
+            FileType::Dir
+        } else if value.is_symlink() {
+            FileType::Symlink
+        } else {
+            FileType::Other
+        }
+    }
+}
+
+impl From<u8> for FileType {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => FileType::File,
+            1 => FileType::Dir,
+            2 => FileType::Symlink,
+            _ => FileType::Other,
+        }
+    }
+}
+
+impl fmt::Display for FileType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            FileType::File => "file",
+            FileType::Dir => "directory",
+            FileType::Symlink => "symlink",
+            FileType::Other => "other",
+        };
+
+        write!(f, "{name}")
+    }
+}
+
 #[derive(Debug, Message)]
 pub struct ListFileResponse {
     file_name_length: u32,
@@ -200,6 +603,7 @@ pub struct ListFileResponse {
     created: i64,
     modified: i64,
     mode: u32,
+    file_type: u8,
 }
 
 impl From<QFile> for ListFileResponse {
@@ -212,8 +616,9 @@ impl fmt::Display for ListFileResponse {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "{}\n\tSize: {} bytes\n\tAccessed: {:?}\n\tCreated: {:?}\n\tModified: {:?}",
+            "{}\n\tType: {}\n\tSize: {} bytes\n\tAccessed: {:?}\n\tCreated: {:?}\n\tModified: {:?}",
             self.file_name,
+            self.file_type(),
             self.file_len,
             self.accessed(),
             self.created(),
@@ -222,15 +627,25 @@ impl fmt::Display for ListFileResponse {
     }
 }
 
+/// The number of [ResumeFile]s a [GetFilesRequest] can be followed by
+pub const MAX_RESUME_FILES: u32 = 1 << 16;
+
+/// Downloads the files below `path` over `num_streams` uni streams opened by the server.
+/// It's followed by `num_resume_files` [ResumeFile] messages, at most [MAX_RESUME_FILES].
 #[derive(Debug, Message)]
 pub struct GetFilesRequest {
     path_len: u32,
     path: String,
     request_id: u32,
     num_streams: u32,
+    num_resume_files: u32,
 }
 
 impl GetFilesRequest {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
     pub fn num_streams(&self) -> u32 {
         self.num_streams
     }
@@ -239,21 +654,507 @@ impl GetFilesRequest {
         self.request_id
     }
 
-    pub fn new(path: String, num_streams: u32) -> Self {
+    pub fn num_resume_files(&self) -> u32 {
+        self.num_resume_files
+    }
+
+    pub fn new(request_id: u32, path: String, num_streams: u32, num_resume_files: u32) -> Self {
         GetFilesRequest {
             path_len: path.len() as u32,
             path,
-            request_id: 1243,
+            request_id,
             num_streams,
+            num_resume_files,
+        }
+    }
+}
+
+/// A file the client already has (partially), sent after a [GetFilesRequest].
+/// If `modified` matches the file on the server, the server only sends the contents after `offset`.
+/// If `offset` is the size of the file, the file isn't sent at all.
+#[derive(Debug, Message)]
+pub struct ResumeFile {
+    path_len: u32,
+    path: String,
+    offset: u64,
+    modified: i64,
+}
+
+impl ResumeFile {
+    pub fn new(path: String, offset: u64, modified: i64) -> Self {
+        ResumeFile {
+            path_len: path.len() as u32,
+            path,
+            offset,
+            modified,
         }
     }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// The modification time in seconds since the unix epoch
+    pub fn modified(&self) -> i64 {
+        self.modified
+    }
 }
 
+/// Sent once at the start of every stream of a [GetFilesRequest] or [PutFilesRequest].
+/// It's followed by `num_files` pairs of a [FileHeader] and the contents of that file.
 #[derive(Debug, Message)]
-pub struct GetFileResponseHeader {
+pub struct FileStreamHeader {
     pub num_files: u32,
 }
 
+/// Uploads files to `path` on the server.
+/// The files are sent over `num_streams` uni streams opened by the client.
+#[derive(Debug, Message)]
+pub struct PutFilesRequest {
+    path_len: u32,
+    path: String,
+    request_id: u32,
+    num_streams: u32,
+}
+
+impl PutFilesRequest {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn num_streams(&self) -> u32 {
+        self.num_streams
+    }
+
+    pub fn request_id(&self) -> u32 {
+        self.request_id
+    }
+
+    pub fn new(request_id: u32, path: String, num_streams: u32) -> Self {
+        PutFilesRequest {
+            path_len: path.len() as u32,
+            path,
+            request_id,
+            num_streams,
+        }
+    }
+}
+
+/// Creates the directory at `path` on the server. The parent directory has to exist.
+#[derive(Debug, Message)]
+pub struct MakeDirRequest {
+    path_len: u32,
+    path: String,
+    request_id: u32,
+}
+
+impl MakeDirRequest {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn request_id(&self) -> u32 {
+        self.request_id
+    }
+
+    pub fn new(request_id: u32, path: String) -> Self {
+        MakeDirRequest {
+            path_len: path.len() as u32,
+            path,
+            request_id,
+        }
+    }
+}
+
+/// Removes the directory at `path` on the server.
+/// Unless `recursive` is set the directory has to be empty.
+#[derive(Debug, Message)]
+pub struct RemoveDirRequest {
+    path_len: u32,
+    path: String,
+    request_id: u32,
+    recursive: u8,
+}
+
+impl RemoveDirRequest {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn request_id(&self) -> u32 {
+        self.request_id
+    }
+
+    pub fn recursive(&self) -> bool {
+        self.recursive != 0
+    }
+
+    pub fn new(request_id: u32, path: String, recursive: bool) -> Self {
+        RemoveDirRequest {
+            path_len: path.len() as u32,
+            path,
+            request_id,
+            recursive: recursive as u8,
+        }
+    }
+}
+
+/// Removes the file or symlink at `path` on the server. Directories are removed with a [RemoveDirRequest].
+#[derive(Debug, Message)]
+pub struct RemoveRequest {
+    path_len: u32,
+    path: String,
+    request_id: u32,
+}
+
+impl RemoveRequest {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn request_id(&self) -> u32 {
+        self.request_id
+    }
+
+    pub fn new(request_id: u32, path: String) -> Self {
+        RemoveRequest {
+            path_len: path.len() as u32,
+            path,
+            request_id,
+        }
+    }
+}
+
+/// Renames the file or directory at `path` on the server to `new_name`, keeping it in the same directory.
+#[derive(Debug, Message)]
+pub struct RenameRequest {
+    path_len: u32,
+    path: String,
+    new_name_len: u32,
+    new_name: String,
+    request_id: u32,
+}
+
+impl RenameRequest {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn new_name(&self) -> &str {
+        &self.new_name
+    }
+
+    pub fn request_id(&self) -> u32 {
+        self.request_id
+    }
+
+    pub fn new(request_id: u32, path: String, new_name: String) -> Self {
+        RenameRequest {
+            path_len: path.len() as u32,
+            path,
+            new_name_len: new_name.len() as u32,
+            new_name,
+            request_id,
+        }
+    }
+}
+
+/// Moves the file or directory at `path` on the server to `destination`.
+/// If `destination` is an existing directory, the file is moved into it.
+#[derive(Debug, Message)]
+pub struct MoveRequest {
+    path_len: u32,
+    path: String,
+    destination_len: u32,
+    destination: String,
+    request_id: u32,
+}
+
+impl MoveRequest {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn destination(&self) -> &str {
+        &self.destination
+    }
+
+    pub fn request_id(&self) -> u32 {
+        self.request_id
+    }
+
+    pub fn new(request_id: u32, path: String, destination: String) -> Self {
+        MoveRequest {
+            path_len: path.len() as u32,
+            path,
+            destination_len: destination.len() as u32,
+            destination,
+            request_id,
+        }
+    }
+}
+
+/// Cancels the running request `request_id`. The server stops the transfer, resets the streams of the request
+/// and answers it with an [ErrorResponse]. The CancelRequest itself isn't answered.
+#[derive(Debug, Message)]
+pub struct CancelRequest {
+    request_id: u32,
+}
+
+impl CancelRequest {
+    /// The id of the request that is cancelled
+    pub fn request_id(&self) -> u32 {
+        self.request_id
+    }
+
+    pub fn new(request_id: u32) -> Self {
+        CancelRequest { request_id }
+    }
+}
+
+/// Sent on the control stream once a request completed successfully.
+/// Requests transferring data send it after all of their streams have been finished.
+#[derive(Debug, Message)]
+pub struct StatusResponse {
+    request_id: u32,
+}
+
+impl StatusResponse {
+    pub fn request_id(&self) -> u32 {
+        self.request_id
+    }
+
+    pub fn new(request_id: u32) -> Self {
+        StatusResponse { request_id }
+    }
+}
+
+/// Why a request failed. Sent in an [ErrorResponse]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ErrorCode {
+    NotFound = 1,
+    PermissionDenied = 2,
+    /// The path is malformed or points outside of the base path
+    InvalidPath = 3,
+    TooManyStreams = 4,
+    /// The client and the server don't support a common protocol version
+    UnsupportedVersion = 5,
+    /// The client cancelled the request with a [CancelRequest]
+    Cancelled = 6,
+    /// The [GetFilesRequest] was followed by more than [MAX_RESUME_FILES] resume files
+    TooManyResumeFiles = 7,
+    /// Anything else that went wrong on the server
+    Internal = 255,
+}
+
+impl From<u8> for ErrorCode {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => ErrorCode::NotFound,
+            2 => ErrorCode::PermissionDenied,
+            3 => ErrorCode::InvalidPath,
+            4 => ErrorCode::TooManyStreams,
+            5 => ErrorCode::UnsupportedVersion,
+            6 => ErrorCode::Cancelled,
+            7 => ErrorCode::TooManyResumeFiles,
+            _ => ErrorCode::Internal,
+        }
+    }
+}
+
+impl From<&Error> for ErrorCode {
+    fn from(value: &Error) -> Self {
+        let io_error_code = |e: &std::io::Error| match e.kind() {
+            std::io::ErrorKind::NotFound => ErrorCode::NotFound,
+            std::io::ErrorKind::PermissionDenied => ErrorCode::PermissionDenied,
+            _ => ErrorCode::Internal,
+        };
+
+        match value {
+            Error::IOError(e) | Error::FileError(FileError::IOError(e)) => io_error_code(e),
+            Error::FileError(
+                FileError::PathIsAbsolute
+                | FileError::InvalidPath(_)
+                | FileError::PathOutsideBasePath(_),
+            ) => ErrorCode::InvalidPath,
+            Error::FileError(FileError::PermissionDenied(_)) => ErrorCode::PermissionDenied,
+            Error::TooManyStreams(_) => ErrorCode::TooManyStreams,
+            Error::NegotiationError => ErrorCode::UnsupportedVersion,
+            Error::Cancelled => ErrorCode::Cancelled,
+            Error::TooManyResumeFiles(_) => ErrorCode::TooManyResumeFiles,
+            _ => ErrorCode::Internal,
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ErrorCode::NotFound => "not found",
+            ErrorCode::PermissionDenied => "permission denied",
+            ErrorCode::InvalidPath => "invalid path",
+            ErrorCode::TooManyStreams => "too many streams",
+            ErrorCode::UnsupportedVersion => "unsupported version",
+            ErrorCode::Cancelled => "cancelled",
+            ErrorCode::TooManyResumeFiles => "too many resume files",
+            ErrorCode::Internal => "internal error",
+        };
+
+        write!(f, "{name}")
+    }
+}
+
+/// Sent on the control stream instead of a [StatusResponse] if a request failed.
+/// Errors that don't belong to a request, like a failed version negotiation, use the `request_id` 0.
+#[derive(Debug, Message)]
+pub struct ErrorResponse {
+    request_id: u32,
+    error_code: u8,
+    message_len: u32,
+    message: String,
+}
+
+impl ErrorResponse {
+    pub fn request_id(&self) -> u32 {
+        self.request_id
+    }
+
+    pub fn error_code(&self) -> ErrorCode {
+        self.error_code.into()
+    }
+
+    /// A human readable description of the error
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn new(request_id: u32, error: &Error) -> Self {
+        // the whole chain of errors is sent, the outer errors are too generic on their own
+        let mut message = error.to_string();
+        let mut source = std::error::Error::source(error);
+        while let Some(e) = source {
+            message.push_str(&format!(": {e}"));
+            source = e.source();
+        }
+
+        ErrorResponse {
+            request_id,
+            error_code: ErrorCode::from(error) as u8,
+            message_len: message.len() as u32,
+            message,
+        }
+    }
+}
+
+impl From<ErrorResponse> for Error {
+    fn from(value: ErrorResponse) -> Self {
+        Error::RequestFailed(value.error_code(), value.message)
+    }
+}
+
+/// Precedes the contents of every file sent over a stream.
+/// The `file_len` bytes directly following it belong to the file at `path`.
+#[derive(Debug, Message)]
+pub struct FileHeader {
+    path_len: u32,
+    path: String,
+    file_type: u8,
+    file_len: u64,
+    offset: u64,
+    mode: u32,
+    modified: i64,
+}
+
+impl From<&QFile> for FileHeader {
+    fn from(value: &QFile) -> Self {
+        let mut header = FileHeader::new(value.relative_path.display(), &value.metadata);
+        header.offset = value.offset;
+
+        header
+    }
+}
+
+impl FileHeader {
+    pub fn new(path: impl ToString, metadata: &Metadata) -> Self {
+        let path = path.to_string();
+        let file_type = FileType::from(metadata.file_type());
+        FileHeader {
+            path_len: path.len() as u32,
+            path,
+            file_type: file_type as u8,
+            // only the contents of regular files are sent
+            file_len: match file_type {
+                FileType::File => metadata.size(),
+                _ => 0,
+            },
+            offset: 0,
+            mode: metadata.mode(),
+            modified: metadata.mtime(),
+        }
+    }
+
+    /// The path of the file relative to the requested path
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn file_type(&self) -> FileType {
+        self.file_type.into()
+    }
+
+    /// The size of the whole file
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> u64 {
+        self.file_len
+    }
+
+    /// The offset the contents start at. Only `len - offset` bytes follow this header.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// The unix permission bits of the file
+    pub fn mode(&self) -> u32 {
+        self.mode
+    }
+
+    pub fn modified(&self) -> SystemTime {
+        let duration = Duration::from_secs(self.modified.unsigned_abs());
+
+        if self.modified < 0 {
+            SystemTime::UNIX_EPOCH - duration
+        } else {
+            SystemTime::UNIX_EPOCH + duration
+        }
+    }
+}
+
+/// Follows the contents of every regular file sent over a stream.
+/// `hash` is the BLAKE3 hash of the whole file, including the part before the offset of a resumed file.
+#[derive(Debug, Message)]
+pub struct FileTrailer {
+    hash_len: u8,
+    hash: Vec<u8>,
+}
+
+impl FileTrailer {
+    pub fn new(hash: &blake3::Hash) -> Self {
+        FileTrailer {
+            hash_len: blake3::OUT_LEN as u8,
+            hash: hash.as_bytes().to_vec(),
+        }
+    }
+
+    pub fn hash(&self) -> &[u8] {
+        &self.hash
+    }
+}
+
 impl ListFileResponse {
     pub fn new(file_name: impl ToString, metadata: &Metadata) -> Self {
         let file_name = file_name.to_string();
@@ -265,6 +1166,7 @@ impl ListFileResponse {
             created: metadata.ctime(),
             modified: metadata.mtime(),
             mode: metadata.mode(),
+            file_type: FileType::from(metadata.file_type()) as u8,
         }
     }
 
@@ -272,6 +1174,10 @@ impl ListFileResponse {
         &self.file_name
     }
 
+    pub fn file_type(&self) -> FileType {
+        self.file_type.into()
+    }
+
     #[allow(clippy::len_without_is_empty)]
     pub fn len(&self) -> u64 {
         self.file_len
@@ -323,4 +1229,114 @@ mod test {
             login.to_bytes().as_slice()
         );
     }
+
+    #[test]
+    fn test_list_files_request_max_depth() {
+        let max_depth =
+            |max_depth| ListFilesRequest::new(1, "/".to_string(), max_depth).max_depth();
+        assert_eq!(max_depth(None), None);
+        assert_eq!(max_depth(Some(0)), Some(1));
+        assert_eq!(max_depth(Some(1)), Some(1));
+        assert_eq!(max_depth(Some(3)), Some(3));
+    }
+
+    #[test]
+    fn test_login_methods() {
+        let methods = LoginMethods {
+            len: 3,
+            methods: vec![2, 7, 1],
+        };
+        assert_eq!(
+            methods.methods().collect::<Vec<_>>(),
+            [LoginMethod::PublicKey, LoginMethod::Password]
+        );
+        assert_eq!([3, 2, 7, 1], methods.to_bytes().as_slice());
+        assert_eq!(LoginMethod::try_from(3), Ok(LoginMethod::Certificate));
+
+        assert_eq!(LoginMethodResponse::new(None).method(), None);
+        assert_eq!(
+            LoginMethodResponse::new(Some(LoginMethod::PublicKey)).to_bytes(),
+            [2]
+        );
+    }
+
+    #[test]
+    fn test_file_header() {
+        let header = FileHeader {
+            path_len: 3,
+            path: "a/b".to_string(),
+            file_type: 0,
+            file_len: 2,
+            offset: 1,
+            mode: 0o100644,
+            modified: 1,
+        };
+        assert_eq!(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1),
+            header.modified()
+        );
+
+        assert_eq!(
+            [
+                0, 0, 0, 3, 0x61, 0x2f, 0x62, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 1, 0,
+                0, 0x81, 0xa4, 0, 0, 0, 0, 0, 0, 0, 1
+            ],
+            header.to_bytes().as_slice()
+        );
+
+        let before_epoch = FileHeader {
+            path_len: 3,
+            path: "a/b".to_string(),
+            file_type: 0,
+            file_len: 2,
+            offset: 1,
+            mode: 0o100644,
+            modified: -1,
+        };
+        assert_eq!(
+            SystemTime::UNIX_EPOCH - Duration::from_secs(1),
+            before_epoch.modified()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_too_many_resume_files() {
+        let request = GetFilesRequest::new(1, "a".to_string(), 1, MAX_RESUME_FILES + 1);
+        let mut bytes = 0x02u16.to_be_bytes().to_vec();
+        bytes.extend(request.to_bytes());
+
+        // none of the resume files are read, so the missing ones don't fail the request
+        let request = Request::next_request(&mut bytes.as_slice()).await.unwrap();
+        assert!(matches!(
+            request,
+            Some(Request::TooManyResumeFiles(request)) if request.request_id() == 1
+        ));
+    }
+
+    #[test]
+    fn test_error_response() {
+        let error = Error::FileError(FileError::PathOutsideBasePath("../a".to_string()));
+        let response = ErrorResponse::new(1, &error);
+        assert_eq!(response.request_id(), 1);
+        assert_eq!(response.error_code(), ErrorCode::InvalidPath);
+
+        let error = Error::FileError(FileError::IOError(std::io::ErrorKind::NotFound.into()));
+        assert_eq!(ErrorCode::from(&error), ErrorCode::NotFound);
+        let error = Error::IOError(std::io::ErrorKind::PermissionDenied.into());
+        assert_eq!(ErrorCode::from(&error), ErrorCode::PermissionDenied);
+        assert_eq!(
+            ErrorCode::from(&Error::TooManyStreams(1 << 20)),
+            ErrorCode::TooManyStreams
+        );
+        assert_eq!(
+            ErrorCode::from(&Error::LoginError(String::new())),
+            ErrorCode::Internal
+        );
+        assert_eq!(ErrorCode::from(&Error::Cancelled), ErrorCode::Cancelled);
+        assert_eq!(
+            ErrorCode::from(&Error::TooManyResumeFiles(MAX_RESUME_FILES + 1)),
+            ErrorCode::TooManyResumeFiles
+        );
+        assert_eq!(ErrorCode::from(42), ErrorCode::Internal);
+    }
 }
@@ -1,16 +1,21 @@
-use crate::auth::{AuthManager, FileStorage};
-use crate::Error;
+use crate::auth::{AuthManager, FileStorage, LockoutPolicy, SharedAuthManager, Storage};
+use crate::{CloseCode, Error};
 use quinn::Endpoint;
+use rustls::server::AllowAnyAnonymousOrAuthenticatedClient;
 use rustls::ServerConfig;
-use rustls::{Certificate, PrivateKey};
+use rustls::{Certificate, PrivateKey, RootCertStore};
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::sync::Mutex;
-use tracing::debug;
+use tracing::{debug, error, warn};
 
 use crate::connected_client::ConnectedClient;
 use crate::files::FileManager;
+use crate::message::LoginMethod;
+
+const DEFAULT_MAX_CONNECTIONS: u32 = 256;
+const DEFAULT_MAX_STREAMS_PER_REQUEST: u16 = 64;
 
 #[derive(Debug)]
 pub struct ServerBuilder {
@@ -18,6 +23,11 @@ pub struct ServerBuilder {
     listen_addr: Option<SocketAddr>,
     base_path: Option<PathBuf>,
     auth_file: Option<PathBuf>,
+    storage: Option<Box<dyn Storage + Send>>,
+    max_connections: u32,
+    max_streams_per_request: u16,
+    login_methods: Vec<LoginMethod>,
+    lockout_policy: LockoutPolicy,
 }
 
 impl ServerBuilder {
@@ -46,13 +56,54 @@ impl ServerBuilder {
         self
     }
 
-    /// set the location for the client authentication file
+    /// set the location for the client authentication file. The users are stored in a [FileStorage]
     pub fn set_auth_file(mut self, auth_file: PathBuf) -> Self {
         if !auth_file.is_file() {
             panic!("auth_file has to be a path to a file");
         }
 
         self.auth_file = Some(auth_file);
+        self.storage = None;
+
+        self
+    }
+
+    /// set the [Storage] the users are looked up in, instead of an [auth file](ServerBuilder::set_auth_file)
+    pub fn with_storage(mut self, storage: impl Storage + Send + 'static) -> Self {
+        self.storage = Some(Box::new(storage));
+        self.auth_file = None;
+
+        self
+    }
+
+    /// set the maximum number of connections, further clients are refused until a connection is closed.
+    /// Defaults to 256
+    pub fn set_max_connections(mut self, max_connections: u32) -> Self {
+        self.max_connections = max_connections;
+
+        self
+    }
+
+    /// set the maximum number of streams a single request can transfer files over, requests asking for more fail.
+    /// Defaults to 64
+    pub fn set_max_streams_per_request(mut self, max_streams_per_request: u16) -> Self {
+        self.max_streams_per_request = max_streams_per_request;
+
+        self
+    }
+
+    /// set the methods clients can log in with. Defaults to [LoginMethod::Password] and [LoginMethod::PublicKey].
+    /// [LoginMethod::Certificate] is supported regardless, for clients presenting a [certificate](ServerBuilder::with_client_auth)
+    pub fn set_login_methods(mut self, login_methods: Vec<LoginMethod>) -> Self {
+        self.login_methods = login_methods;
+
+        self
+    }
+
+    /// set how often logins for a user name or from an address can fail before they are locked out.
+    /// Defaults to [LockoutPolicy::default]
+    pub fn set_lockout_policy(mut self, lockout_policy: LockoutPolicy) -> Self {
+        self.lockout_policy = lockout_policy;
 
         self
     }
@@ -71,16 +122,38 @@ impl ServerBuilder {
         self
     }
 
-    pub async fn build(self) -> Result<Server, Error> {
-        let server = Server::new(
-            self.listen_addr.expect("didn't set listen_addr"),
-            self.server_config.expect("didn't set ServerConfig"),
-            self.auth_file.expect("didn't set auth_file"),
-            self.base_path.expect("didn't set base_path"),
-        )
-        .await?;
+    /// Like [with_certs](ServerBuilder::with_certs), but also asks the clients for a certificate signed by one of
+    /// `client_ca_certs`.
+    ///
+    /// A client presenting a certificate is logged in as the user the certificate is
+    /// [mapped to](crate::auth::AuthManager::set_certificates), without a password.
+    /// Clients without a certificate still log in with their password.
+    pub fn with_client_auth(
+        mut self,
+        certs: Vec<Certificate>,
+        private_key: PrivateKey,
+        client_ca_certs: Vec<Certificate>,
+    ) -> Self {
+        let mut store = RootCertStore::empty();
+        for cert in client_ca_certs {
+            store
+                .add(&cert)
+                .expect("failed to add client CA certificate to store")
+        }
 
-        Ok(server)
+        let server_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(AllowAnyAnonymousOrAuthenticatedClient::new(store))
+            .with_single_cert(certs, private_key)
+            .expect("Couldn't construct new ServerConfig");
+
+        self.server_config = Some(server_config);
+
+        self
+    }
+
+    pub async fn build(self) -> Result<Server, Error> {
+        Server::new(self).await
     }
 }
 
@@ -88,8 +161,10 @@ impl ServerBuilder {
 #[derive(Debug)]
 pub struct Server {
     endpoint: Endpoint,
-    auth: Arc<Mutex<AuthManager<FileStorage>>>,
+    auth: SharedAuthManager,
     file_manager: Arc<FileManager>,
+    max_streams_per_request: u16,
+    login_methods: Arc<[LoginMethod]>,
 }
 
 impl Server {
@@ -99,39 +174,49 @@ impl Server {
             listen_addr: None,
             base_path: None,
             auth_file: None,
+            storage: None,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_streams_per_request: DEFAULT_MAX_STREAMS_PER_REQUEST,
+            login_methods: vec![LoginMethod::Password, LoginMethod::PublicKey],
+            lockout_policy: LockoutPolicy::default(),
         }
     }
 
     fn create_endpoint(
         listen_addr: SocketAddr,
         server_config: ServerConfig,
+        max_connections: u32,
     ) -> Result<Endpoint, Error> {
-        let server_config = quinn::ServerConfig::with_crypto(Arc::new(server_config));
+        let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(server_config));
+        server_config.concurrent_connections(max_connections);
         let server = Endpoint::server(server_config, listen_addr)?;
         Ok(server)
     }
 
-    /// Creates a new `Server` listening on the given addr
-    ///
-    /// # Arguments
+    /// Creates a new `Server` listening on the addr set in `builder`.
     ///
-    /// * `listen_addr` - The addr to listen on
-    /// * `cert` - The certificate to present to a connecting client. Refer to [rustls](rustls::Certificate) documentation for the correct format
-    /// * `priv_key` - The private key. Refer to [rustls](rustls::PrivateKey) documentation for the correct format
-    pub async fn new(
-        listen_addr: SocketAddr,
-        server_config: ServerConfig,
-        auth_file: PathBuf,
-        base_path: PathBuf,
-    ) -> Result<Self, Error> {
-        let server = Server::create_endpoint(listen_addr, server_config)?;
-        let auth_storage = FileStorage::new(auth_file).await?;
-        let manager = AuthManager::new(auth_storage);
-        let file_manager = FileManager::new(base_path).unwrap();
+    /// # Panic
+    /// This function panics if the listen addr, the [ServerConfig], the base path or the users weren't set
+    pub(crate) async fn new(builder: ServerBuilder) -> Result<Self, Error> {
+        let storage: Box<dyn Storage + Send> = match (builder.storage, builder.auth_file) {
+            (Some(storage), _) => storage,
+            (None, Some(auth_file)) => Box::new(FileStorage::new(auth_file).await?),
+            (None, None) => panic!("didn't set auth_file or storage"),
+        };
+        let server = Server::create_endpoint(
+            builder.listen_addr.expect("didn't set listen_addr"),
+            builder.server_config.expect("didn't set ServerConfig"),
+            builder.max_connections,
+        )?;
+        let manager = AuthManager::new(storage).with_lockout_policy(builder.lockout_policy);
+        let file_manager =
+            FileManager::new(builder.base_path.expect("didn't set base_path")).unwrap();
         Ok(Server {
             endpoint: server,
             auth: Arc::new(Mutex::new(manager)),
             file_manager: Arc::new(file_manager),
+            max_streams_per_request: builder.max_streams_per_request,
+            login_methods: builder.login_methods.into(),
         })
     }
 
@@ -145,9 +230,60 @@ impl Server {
                     connection,
                     self.auth.clone(),
                     self.file_manager.clone(),
+                    self.login_methods.clone(),
+                    self.max_streams_per_request,
                 )
                 .await;
             }
         }
     }
+
+    /// Accepts clients and handles their requests until the endpoint is closed.
+    ///
+    /// Every client is handled in its own task, so a slow handshake doesn't block other clients.
+    /// A client is disconnected once it finished the control stream.
+    pub async fn serve(&self) -> Result<(), Error> {
+        while let Some(connecting) = self.endpoint.accept().await {
+            let auth = self.auth.clone();
+            let file_manager = self.file_manager.clone();
+            let login_methods = self.login_methods.clone();
+            let max_streams_per_request = self.max_streams_per_request;
+            tokio::spawn(async move {
+                let connection = match connecting.await {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        warn!("failed to accept a connection: {e}");
+                        return;
+                    }
+                };
+                let remote_address = connection.remote_address();
+                debug!("accepted a new client from {remote_address}");
+
+                let connected_client = ConnectedClient::new(
+                    connection,
+                    auth,
+                    file_manager,
+                    login_methods,
+                    max_streams_per_request,
+                )
+                .await;
+                let result = match connected_client {
+                    Ok(connected_client) => connected_client.run().await,
+                    Err(e) => Err(e),
+                };
+                match result {
+                    Ok(()) => debug!("client {remote_address} disconnected"),
+                    Err(e) => error!("failed to handle client {remote_address}: {e}"),
+                }
+            });
+        }
+
+        debug!("the endpoint was closed");
+        Ok(())
+    }
+
+    /// Closes all connections and stops [serve](Server::serve)
+    pub fn close(&self) {
+        self.endpoint.close(CloseCode::Ok.into(), b"server closed");
+    }
 }
@@ -0,0 +1,504 @@
+use crate::files::{self, FileError, QFile};
+use crate::message::{self, FileType, Message};
+use crate::{Error, StreamCode};
+use quinn::{ReadError, RecvStream, SendStream};
+use std::collections::HashMap;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::sync::watch;
+use tokio::task::{JoinError, JoinSet};
+use tracing::{debug, error, trace};
+
+// Sending and receiving a set of files over multiple streams.
+// Every stream starts with a FileStreamHeader, followed by a FileHeader, the contents and a FileTrailer for every file.
+// Directories are only sent as a FileHeader without any contents or trailer.
+// This is used by the server for GetFiles and by the client for PutFiles.
+
+/// Signals a transfer that its request was cancelled, once `true` is sent
+pub(crate) type Cancel = watch::Receiver<bool>;
+
+/// A [Cancel] for transfers that can't be cancelled
+pub(crate) fn never_cancelled() -> Cancel {
+    watch::channel(false).1
+}
+
+/// Resolves once the request is cancelled. If the sender is dropped without cancelling, it never resolves.
+pub(crate) async fn cancelled(cancel: &mut Cancel) {
+    while !*cancel.borrow() {
+        if cancel.changed().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Turns a worker task that panicked or was aborted into an error, instead of panicking the task that joins it
+pub(crate) fn join_error(e: JoinError) -> Error {
+    error!("JoinError while joining a worker task: {e}");
+    std::io::Error::other(e).into()
+}
+
+/// A stream that is aborted if its transfer is cancelled, so the peer doesn't mistake it for a complete transfer
+pub(crate) trait Abort {
+    fn abort(&mut self);
+}
+
+impl Abort for SendStream {
+    fn abort(&mut self) {
+        // fails if the stream was already finished or stopped by the peer, it's done either way
+        let _ = self.reset(StreamCode::Cancelled.into());
+    }
+}
+
+impl Abort for RecvStream {
+    fn abort(&mut self) {
+        let _ = self.stop(StreamCode::Cancelled.into());
+    }
+}
+
+/// Aborts the stream it holds when dropped, e.g. because the task transferring over it was aborted when
+/// the transfer was dropped. A dropped [SendStream] would otherwise be finished like a complete transfer.
+struct AbortOnDrop<T: Abort>(Option<T>);
+
+impl<T: Abort> AbortOnDrop<T> {
+    fn new(stream: T) -> Self {
+        AbortOnDrop(Some(stream))
+    }
+
+    fn get_mut(&mut self) -> &mut T {
+        self.0
+            .as_mut()
+            .expect("the stream is only taken by into_inner")
+    }
+
+    /// Takes the stream out, it isn't aborted anymore
+    fn into_inner(mut self) -> T {
+        self.0
+            .take()
+            .expect("the stream is only taken by into_inner")
+    }
+}
+
+impl<T: Abort> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        if let Some(stream) = &mut self.0 {
+            stream.abort();
+        }
+    }
+}
+
+/// Whether `e` is caused by the peer resetting the stream with [StreamCode::Cancelled]
+fn cancelled_by_peer(e: &Error) -> bool {
+    let e = match e {
+        Error::IOError(e) | Error::FileError(FileError::IOError(e)) => e,
+        _ => return false,
+    };
+    matches!(
+        e.get_ref().and_then(|e| e.downcast_ref::<ReadError>()),
+        Some(ReadError::Reset(code)) if *code == StreamCode::Cancelled.into()
+    )
+}
+
+/// Applies the [ResumeFile](message::ResumeFile)s of a client to `files`.
+/// Files the client already has completely are dropped, partially received files are only sent from their offset.
+/// Nothing is resumed if the modification time doesn't match, since the file changed in the meantime.
+pub(crate) fn resume_files(
+    files: Vec<QFile>,
+    resume_files: Vec<message::ResumeFile>,
+) -> Vec<QFile> {
+    let resume_files: HashMap<PathBuf, message::ResumeFile> = resume_files
+        .into_iter()
+        .map(|file| (PathBuf::from(file.path()), file))
+        .collect();
+
+    files
+        .into_iter()
+        .filter_map(|mut file| {
+            let resume_file = match resume_files.get(&file.relative_path) {
+                Some(resume_file) if file.metadata.is_file() => resume_file,
+                _ => return Some(file),
+            };
+
+            if resume_file.modified() != file.metadata.mtime() {
+                trace!("{:?} changed, sending it completely", file.relative_path);
+                return Some(file);
+            }
+
+            match resume_file.offset() {
+                offset if offset == file.metadata.len() => {
+                    trace!("{:?} is already complete, skipping it", file.relative_path);
+                    None
+                }
+                offset if offset < file.metadata.len() => {
+                    trace!("resuming {:?} at {offset}", file.relative_path);
+                    file.offset = offset;
+                    Some(file)
+                }
+                _ => Some(file),
+            }
+        })
+        .collect()
+}
+
+/// Spreads `files` over `streams` and sends them. Returns the streams, so they can be finished by the caller.
+/// If sending failed on any of the streams, all streams are still sent to the end and the first error is returned.
+/// Once `cancel` fires, every stream is aborted and [Error::Cancelled] is returned.
+pub(crate) async fn send_files<T>(
+    files: Vec<QFile>,
+    mut streams: Vec<T>,
+    cancel: Cancel,
+) -> Result<Vec<T>, Error>
+where
+    T: AsyncWrite + Abort + Send + Sync + Unpin + 'static,
+{
+    let num_streams = streams.len();
+    // symlinks are never followed, so they can't be transferred
+    let files = files.into_iter().filter(|file| !file.metadata.is_symlink());
+
+    // TODO: files are statically distributed round-robin. It would probably be better
+    // to let the streams pick up the next file once they are done with their current one
+    let mut partitions: Vec<Vec<QFile>> = (0..num_streams).map(|_| Vec::new()).collect();
+    for (i, file) in files.enumerate() {
+        partitions[i % num_streams].push(file);
+    }
+
+    let mut join_set: JoinSet<Result<T, Error>> = JoinSet::new();
+    for (i, files) in partitions.into_iter().enumerate() {
+        trace!("spawning thread {i} to handle file sending");
+        let writer = streams.pop().expect("we have less streams than partitions");
+        let mut cancel = cancel.clone();
+
+        join_set.spawn(async move {
+            let mut writer = AbortOnDrop::new(writer);
+            let result = tokio::select! {
+                result = send_stream(writer.get_mut(), files) => result,
+                _ = cancelled(&mut cancel) => Err(Error::Cancelled),
+            };
+            match result {
+                Ok(()) => Ok(writer.into_inner()),
+                Err(Error::Cancelled) => {
+                    debug!("cancelled sending files, aborting the stream");
+                    Err(Error::Cancelled)
+                }
+                // the stream is finished, the peer notices the missing files
+                Err(e) => {
+                    drop(writer.into_inner());
+                    Err(e)
+                }
+            }
+        });
+    }
+
+    let mut finished_streams = Vec::with_capacity(num_streams);
+    let mut result = Ok(());
+    while let Some(res) = join_set.join_next().await {
+        match res.unwrap_or_else(|e| Err(join_error(e))) {
+            Ok(writer) => finished_streams.push(writer),
+            Err(e) => {
+                error!("Error in send_files worker thread: {e}");
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+    }
+
+    result.map(|()| finished_streams)
+}
+
+async fn send_stream<T>(writer: &mut T, files: Vec<QFile>) -> Result<(), Error>
+where
+    T: AsyncWrite + Send + Sync + Unpin,
+{
+    let header = message::FileStreamHeader {
+        num_files: files.len() as u32,
+    };
+    header.send(writer).await?;
+
+    for mut file in files {
+        trace!("Got {file:?} to send");
+        message::FileHeader::from(&file).send(writer).await?;
+        if file.metadata.is_file() {
+            let hash = file.send(writer).await?;
+            message::FileTrailer::new(&hash).send(writer).await?;
+        }
+    }
+    trace!("all files sent");
+    Ok(())
+}
+
+/// Receives the files of every stream in parallel.
+/// `target_path` maps the path of every received file to the path it should be written to.
+/// Once `cancel` fires, every stream is aborted and [Error::Cancelled] is returned.
+pub(crate) async fn recv_files<T, F>(
+    streams: Vec<T>,
+    target_path: F,
+    cancel: Cancel,
+) -> Result<(), Error>
+where
+    T: AsyncRead + Abort + Send + Sync + Unpin + 'static,
+    F: Fn(&str) -> Result<PathBuf, FileError> + Clone + Send + 'static,
+{
+    let mut join_set = JoinSet::new();
+    for stream in streams {
+        let target_path = target_path.clone();
+        let mut cancel = cancel.clone();
+        join_set.spawn(async move {
+            let mut stream = AbortOnDrop::new(stream);
+            let result = tokio::select! {
+                result = recv_stream(stream.get_mut(), target_path) => result,
+                _ = cancelled(&mut cancel) => Err(Error::Cancelled),
+            };
+            match result {
+                Err(Error::Cancelled) => {
+                    debug!("cancelled receiving files, aborting the stream");
+                    Err(Error::Cancelled)
+                }
+                Err(e) if cancelled_by_peer(&e) => {
+                    debug!("the peer cancelled sending files");
+                    Err(Error::Cancelled)
+                }
+                result => {
+                    drop(stream.into_inner());
+                    result
+                }
+            }
+        });
+    }
+
+    // all streams are received to the end, even if one of them fails
+    // that way every partially received file is left in a state that can be resumed
+    let mut result = Ok(());
+    while let Some(res) = join_set.join_next().await {
+        if let Err(e) = res.unwrap_or_else(|e| Err(join_error(e))) {
+            error!("failed to receive files: {e}");
+            if result.is_ok() {
+                result = Err(e);
+            }
+        }
+    }
+
+    result
+}
+
+async fn recv_stream<T, F>(stream: &mut T, target_path: F) -> Result<(), Error>
+where
+    T: AsyncRead + Send + Sync + Unpin,
+    F: Fn(&str) -> Result<PathBuf, FileError>,
+{
+    let header = message::FileStreamHeader::recv(stream).await?;
+    trace!("receiving {} files on stream", header.num_files);
+
+    for _ in 0..header.num_files {
+        let header = message::FileHeader::recv(stream).await?;
+        let path = target_path(header.path())?;
+        match header.file_type() {
+            FileType::File => files::recv_file(stream, &path, &header).await?,
+            // directories are only created, the files inside of them might be sent on other streams
+            // so the permissions and modification time aren't restored
+            FileType::Dir => tokio::fs::create_dir_all(&path).await?,
+            file_type => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("can't receive {file_type} `{}`", header.path()),
+                )
+                .into())
+            }
+        }
+    }
+
+    // read up to the end of the stream, this makes sure the sender didn't send more than announced
+    // and lets the sender finish the stream cleanly
+    if stream.read(&mut [0]).await? != 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "stream contains data after the last file",
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::files::FileManager;
+    use std::io::Cursor;
+    use std::pin::Pin;
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+    use std::task::{Context, Poll};
+    use tokio::io::{DuplexStream, ReadBuf};
+
+    impl Abort for Vec<u8> {
+        fn abort(&mut self) {}
+    }
+
+    impl Abort for Cursor<Vec<u8>> {
+        fn abort(&mut self) {}
+    }
+
+    /// A stream that remembers if it was aborted
+    #[derive(Debug)]
+    struct AbortableStream {
+        stream: DuplexStream,
+        aborted: Arc<AtomicBool>,
+    }
+
+    impl Abort for AbortableStream {
+        fn abort(&mut self) {
+            self.aborted.store(true, Ordering::SeqCst);
+        }
+    }
+
+    impl AsyncRead for AbortableStream {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.stream).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for AbortableStream {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Pin::new(&mut self.stream).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.stream).poll_flush(cx)
+        }
+
+        fn poll_shutdown(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.stream).poll_shutdown(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cancel() {
+        let path = format!("{}/tests/walk_dir", env!("CARGO_MANIFEST_DIR"));
+        let files = FileManager::new(path)
+            .expect("expect creating a file manager not to fail")
+            .walk_dir("", None)
+            .await
+            .unwrap();
+
+        // nobody reads the other side, so sending blocks until the transfer is cancelled
+        let (stream, _other_side) = tokio::io::duplex(16);
+        let aborted = Arc::new(AtomicBool::new(false));
+        let stream = AbortableStream {
+            stream,
+            aborted: aborted.clone(),
+        };
+        let (cancel, cancel_rx) = watch::channel(false);
+        let sending = tokio::spawn(send_files(files, vec![stream], cancel_rx));
+        tokio::task::yield_now().await;
+        cancel.send(true).unwrap();
+        assert!(matches!(sending.await.unwrap(), Err(Error::Cancelled)));
+        assert!(aborted.load(Ordering::SeqCst));
+
+        // nothing is ever sent, so receiving blocks until the transfer is cancelled
+        let (stream, _other_side) = tokio::io::duplex(16);
+        let aborted = Arc::new(AtomicBool::new(false));
+        let stream = AbortableStream {
+            stream,
+            aborted: aborted.clone(),
+        };
+        let (cancel, cancel_rx) = watch::channel(false);
+        let receiving = tokio::spawn(recv_files(
+            vec![stream],
+            |path: &str| Ok(PathBuf::from(path)),
+            cancel_rx,
+        ));
+        tokio::task::yield_now().await;
+        cancel.send(true).unwrap();
+        assert!(matches!(receiving.await.unwrap(), Err(Error::Cancelled)));
+        assert!(aborted.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_drop_aborts_streams() {
+        let path = format!("{}/tests/walk_dir", env!("CARGO_MANIFEST_DIR"));
+        let files = FileManager::new(path)
+            .expect("expect creating a file manager not to fail")
+            .walk_dir("", None)
+            .await
+            .unwrap();
+
+        // dropping the transfer instead of cancelling it aborts the streams as well
+        let (stream, _other_side) = tokio::io::duplex(16);
+        let aborted = Arc::new(AtomicBool::new(false));
+        let stream = AbortableStream {
+            stream,
+            aborted: aborted.clone(),
+        };
+        let sending = send_files(files, vec![stream], never_cancelled());
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_millis(10), sending)
+                .await
+                .is_err()
+        );
+        // the aborted task drops the stream on the runtime
+        tokio::task::yield_now().await;
+        assert!(aborted.load(Ordering::SeqCst));
+
+        // a stream reset with `Cancelled` by the peer is reported as a cancelled transfer
+        let reset =
+            |code: u32| Error::IOError(ReadError::Reset(quinn::VarInt::from_u32(code)).into());
+        assert!(cancelled_by_peer(&reset(StreamCode::Cancelled as u32)));
+        assert!(!cancelled_by_peer(&reset(0)));
+        assert!(!cancelled_by_peer(&Error::Cancelled));
+    }
+
+    #[tokio::test]
+    async fn test_checksum_mismatch() {
+        let path = format!("{}/tests/walk_dir", env!("CARGO_MANIFEST_DIR"));
+        let files = FileManager::new(path)
+            .expect("expect creating a file manager not to fail")
+            .walk_dir("a", None)
+            .await
+            .unwrap();
+        let target = std::env::temp_dir().join("qftp_test_checksum_mismatch");
+        let _ = std::fs::remove_dir_all(&target);
+        let target_path = {
+            let target = target.clone();
+            move |path: &str| files::local_path(&target, path)
+        };
+
+        let mut stream = send_files(files, vec![Vec::new()], never_cancelled())
+            .await
+            .unwrap()
+            .remove(0);
+        recv_files(
+            vec![Cursor::new(stream.clone())],
+            target_path.clone(),
+            never_cancelled(),
+        )
+        .await
+        .unwrap();
+
+        // the contents of a.bin are followed by the trailer containing the 32 byte hash
+        let corrupted = stream.len() - 64;
+        stream[corrupted] ^= 1;
+        let result = recv_files(vec![Cursor::new(stream)], target_path, never_cancelled()).await;
+        assert!(matches!(result, Err(Error::ChecksumMismatch(path)) if path.ends_with("a.bin")));
+        let partial_files = std::fs::read_dir(target.join("a"))
+            .unwrap()
+            .filter(|entry| files::partial_file_target(&entry.as_ref().unwrap().path()).is_some())
+            .count();
+        assert_eq!(partial_files, 0);
+
+        std::fs::remove_dir_all(&target).unwrap();
+    }
+}
@@ -1,8 +1,24 @@
 #[cfg(test)]
 mod test {
-    use qftp::{Client, QClientConfig, Server};
-    use rustls::{Certificate, PrivateKey};
-    use std::{fs, path::PathBuf, str::FromStr};
+    use qftp::{
+        auth::{
+            certificate_fingerprint, AuthError, AuthManager, FileStorage, InMemoryStorage,
+            LockoutPolicy, User,
+        },
+        credentials::KeyCredentials,
+        message::{ErrorCode, FileType, LoginMethod},
+        Client, CloseCode, Error, QClientConfig, Server, ServerBuilder,
+    };
+    use ring::{rand::SystemRandom, signature::Ed25519KeyPair};
+    use rustls::{Certificate, ClientConfig, PrivateKey};
+    use std::{
+        fs,
+        os::unix::fs::MetadataExt,
+        path::{Path, PathBuf},
+        str::FromStr,
+        sync::Arc,
+        time::Duration,
+    };
     use tracing::Level;
     use tracing_subscriber::filter::EnvFilter;
     fn read_test_certs() -> (Certificate, PrivateKey) {
@@ -14,20 +30,47 @@ mod test {
         (cert, priv_key)
     }
 
-    async fn new_default_server() -> Server {
-        let (cert, priv_key) = read_test_certs();
+    async fn new_default_server(port: u16) -> Server {
         let path = format!("{}/tests/walk_dir", env!("CARGO_MANIFEST_DIR"));
-        let auth_file = format!("{}/tests/auth.json", env!("CARGO_MANIFEST_DIR"));
-        let server = Server::builder()
-            .set_listen_addr("0.0.0.0:2345".parse().unwrap())
-            .set_base_path(PathBuf::from_str(&path).unwrap())
-            .set_auth_file(PathBuf::from_str(&auth_file).unwrap())
+        new_server(port, PathBuf::from_str(&path).unwrap()).await
+    }
+
+    async fn new_server(port: u16, base_path: PathBuf) -> Server {
+        server_builder(port, base_path).build().await.unwrap()
+    }
+
+    fn server_builder(port: u16, base_path: PathBuf) -> ServerBuilder {
+        let (cert, priv_key) = read_test_certs();
+        Server::builder()
+            .set_listen_addr(format!("0.0.0.0:{port}").parse().unwrap())
+            .set_base_path(base_path)
+            .with_storage(InMemoryStorage::from(test_users(Vec::new(), Vec::new())))
             .with_certs(vec![cert], priv_key)
+    }
+
+    /// The users of tests/auth.json, `test_user` can log in with the client certificates with the `fingerprints`
+    /// and the `authorized_keys`
+    fn test_users(fingerprints: Vec<String>, authorized_keys: Vec<String>) -> Vec<User> {
+        let auth_file = format!("{}/tests/auth.json", env!("CARGO_MANIFEST_DIR"));
+        let mut users: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(auth_file).unwrap()).unwrap();
+        // the test user owns the files the tests create, so it may change them
+        let owner = fs::metadata(env!("CARGO_TARGET_TMPDIR")).unwrap();
+        users[0]["uid"] = owner.uid().into();
+        users[0]["gid"] = vec![owner.gid()].into();
+        users[0]["certificates"] = fingerprints.into();
+        users[0]["authorized_keys"] = authorized_keys.into();
+        serde_json::from_value(users).unwrap()
+    }
+
+    async fn new_client(port: u16) -> Result<Client, Error> {
+        let client_config = QClientConfig::dangerous_dont_verify();
+        Client::builder()
+            .set_addr(format!("127.0.0.1:{port}"), "dev.local".to_string())
+            .with_client_config(client_config.into())
+            .with_credentials("test_user".to_string(), "123".to_string())
             .build()
             .await
-            .unwrap();
-
-        server
     }
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
@@ -41,8 +84,9 @@ mod test {
             .init();
 
         let server = tokio::spawn(async {
-            let server = new_default_server().await;
+            let server = new_default_server(2345).await;
             let mut connected_client = server.accept().await.unwrap();
+            assert_eq!(connected_client.version(), 1);
             connected_client
                 .next_request()
                 .await
@@ -53,18 +97,951 @@ mod test {
         let client = tokio::spawn(async {
             std::env::set_var("SSLKEYLOGFILE", "client.keylog");
             let client_config = QClientConfig::dangerous_dont_verify();
-            let mut client = Client::builder()
+            let client = Client::builder()
                 .set_addr("127.0.0.1:2345", "dev.local".to_string())
                 .with_client_config(client_config.into())
+                .with_credentials("test_user".to_string(), "123".to_string())
                 .build()
                 .await
                 .expect("error constructing the client");
-            let result = client.list_files().await.unwrap();
+            assert_eq!(client.version(), 1);
+            let result = client.list_files("/", None).await.unwrap();
             client.shutdown().await.unwrap();
-            assert_eq!(result.len(), 4);
+            assert_eq!(result.len(), 7);
             println!("{result:#?}")
         });
 
         futures::future::join_all(vec![server, client]).await;
     }
+
+    fn read_dir_recursive(path: &Path, result: &mut Vec<PathBuf>) {
+        for entry in fs::read_dir(path).unwrap() {
+            let entry = entry.unwrap();
+            if entry.file_type().unwrap().is_dir() {
+                read_dir_recursive(&entry.path(), result);
+            } else {
+                result.push(entry.path());
+            }
+        }
+    }
+
+    fn assert_dirs_equal(expected: &Path, actual: &Path) {
+        let mut expected_files = Vec::new();
+        read_dir_recursive(expected, &mut expected_files);
+        let mut actual_files = Vec::new();
+        read_dir_recursive(actual, &mut actual_files);
+        assert_eq!(expected_files.len(), actual_files.len());
+
+        for expected_file in expected_files {
+            let relative_path = expected_file.strip_prefix(expected).unwrap();
+            let actual_file = actual.join(relative_path);
+            assert_eq!(
+                fs::read(&expected_file).unwrap(),
+                fs::read(&actual_file).unwrap()
+            );
+
+            let expected_modified = fs::metadata(&expected_file).unwrap().mtime();
+            let actual_modified = fs::metadata(&actual_file).unwrap().mtime();
+            assert_eq!(expected_modified, actual_modified);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn successful_get_files() {
+        let remote_dir = PathBuf::from(format!("{}/tests/walk_dir", env!("CARGO_MANIFEST_DIR")));
+        let local_dir = std::env::temp_dir().join("qftp_successful_get_files");
+        let _ = fs::remove_dir_all(&local_dir);
+
+        let server = tokio::spawn(async {
+            let server = new_default_server(2346).await;
+            let mut connected_client = server.accept().await.unwrap();
+            connected_client
+                .next_request()
+                .await
+                .expect("next request returned err");
+            connected_client.shutdown().await.unwrap();
+        });
+
+        let client_local_dir = local_dir.clone();
+        let client = tokio::spawn(async move {
+            let client_config = QClientConfig::dangerous_dont_verify();
+            let client = Client::builder()
+                .set_addr("127.0.0.1:2346", "dev.local".to_string())
+                .with_client_config(client_config.into())
+                .with_credentials("test_user".to_string(), "123".to_string())
+                .build()
+                .await
+                .expect("error constructing the client");
+            client.get_files("/", &client_local_dir, 2).await.unwrap();
+            client.shutdown().await.unwrap();
+        });
+
+        for result in futures::future::join_all(vec![server, client]).await {
+            result.unwrap();
+        }
+
+        assert_dirs_equal(&remote_dir, &local_dir);
+        fs::remove_dir_all(&local_dir).unwrap();
+    }
+
+    fn copy_modified(from: &Path, to: &Path) {
+        let modified = fs::metadata(from).unwrap().modified().unwrap();
+        fs::File::options()
+            .write(true)
+            .open(to)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn successful_resume_get_files() {
+        let remote_dir = PathBuf::from(format!("{}/tests/walk_dir", env!("CARGO_MANIFEST_DIR")));
+        let local_dir = std::env::temp_dir().join("qftp_successful_resume_get_files");
+        let _ = fs::remove_dir_all(&local_dir);
+        fs::create_dir_all(local_dir.join("a")).unwrap();
+
+        // a partially received file from an interrupted download
+        let partial_file = local_dir.join("a/.a.bin.qftp-part");
+        let content = fs::read(remote_dir.join("a/a.bin")).unwrap();
+        fs::write(&partial_file, &content[..1000]).unwrap();
+        copy_modified(&remote_dir.join("a/a.bin"), &partial_file);
+
+        // a completely received file which shouldn't be touched again
+        let complete_file = local_dir.join("root.txt");
+        fs::copy(remote_dir.join("root.txt"), &complete_file).unwrap();
+        copy_modified(&remote_dir.join("root.txt"), &complete_file);
+        let complete_file_ino = fs::metadata(&complete_file).unwrap().ino();
+
+        let server = tokio::spawn(async {
+            let server = new_default_server(2350).await;
+            let mut connected_client = server.accept().await.unwrap();
+            connected_client
+                .next_request()
+                .await
+                .expect("next request returned err");
+            connected_client.shutdown().await.unwrap();
+        });
+
+        let client_local_dir = local_dir.clone();
+        let client = tokio::spawn(async move {
+            let client_config = QClientConfig::dangerous_dont_verify();
+            let client = Client::builder()
+                .set_addr("127.0.0.1:2350", "dev.local".to_string())
+                .with_client_config(client_config.into())
+                .with_credentials("test_user".to_string(), "123".to_string())
+                .build()
+                .await
+                .expect("error constructing the client");
+            client.get_files("/", &client_local_dir, 2).await.unwrap();
+            client.shutdown().await.unwrap();
+        });
+
+        for result in futures::future::join_all(vec![server, client]).await {
+            result.unwrap();
+        }
+
+        assert_dirs_equal(&remote_dir, &local_dir);
+        assert_eq!(
+            complete_file_ino,
+            fs::metadata(&complete_file).unwrap().ino()
+        );
+        fs::remove_dir_all(&local_dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn successful_put_files() {
+        let local_dir = PathBuf::from(format!("{}/tests/walk_dir", env!("CARGO_MANIFEST_DIR")));
+        let remote_dir = std::env::temp_dir().join("qftp_successful_put_files");
+        let _ = fs::remove_dir_all(&remote_dir);
+        fs::create_dir(&remote_dir).unwrap();
+
+        let server_remote_dir = remote_dir.clone();
+        let server = tokio::spawn(async move {
+            let server = new_server(2347, server_remote_dir).await;
+            let mut connected_client = server.accept().await.unwrap();
+            connected_client
+                .next_request()
+                .await
+                .expect("next request returned err");
+            connected_client.shutdown().await.unwrap();
+        });
+
+        let client_local_dir = local_dir.clone();
+        let client = tokio::spawn(async move {
+            let client_config = QClientConfig::dangerous_dont_verify();
+            let client = Client::builder()
+                .set_addr("127.0.0.1:2347", "dev.local".to_string())
+                .with_client_config(client_config.into())
+                .with_credentials("test_user".to_string(), "123".to_string())
+                .build()
+                .await
+                .expect("error constructing the client");
+            client
+                .put_files(&client_local_dir, "/uploaded", 3)
+                .await
+                .unwrap();
+            client.shutdown().await.unwrap();
+        });
+
+        for result in futures::future::join_all(vec![server, client]).await {
+            result.unwrap();
+        }
+
+        assert_dirs_equal(&local_dir, &remote_dir.join("uploaded"));
+
+        fs::remove_dir_all(&remote_dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn successful_dir_operations() {
+        let remote_dir = std::env::temp_dir().join("qftp_successful_dir_operations_remote");
+        let local_dir = std::env::temp_dir().join("qftp_successful_dir_operations_local");
+        let _ = fs::remove_dir_all(&remote_dir);
+        let _ = fs::remove_dir_all(&local_dir);
+        fs::create_dir(&remote_dir).unwrap();
+
+        let server_remote_dir = remote_dir.clone();
+        let server = tokio::spawn(async move {
+            let server = new_server(2348, server_remote_dir).await;
+            let mut connected_client = server.accept().await.unwrap();
+            for _ in 0..6 {
+                connected_client
+                    .next_request()
+                    .await
+                    .expect("next request returned err");
+            }
+            connected_client.shutdown().await.unwrap();
+        });
+
+        let client_remote_dir = remote_dir.clone();
+        let client_local_dir = local_dir.clone();
+        let client = tokio::spawn(async move {
+            let client_config = QClientConfig::dangerous_dont_verify();
+            let client = Client::builder()
+                .set_addr("127.0.0.1:2348", "dev.local".to_string())
+                .with_client_config(client_config.into())
+                .with_credentials("test_user".to_string(), "123".to_string())
+                .build()
+                .await
+                .expect("error constructing the client");
+            client.make_dir("/new").await.unwrap();
+            client.make_dir("/new/empty").await.unwrap();
+            assert!(client_remote_dir.join("new/empty").is_dir());
+
+            let result = client.list_files("/new", None).await.unwrap();
+            assert_eq!(result.len(), 1);
+            assert_eq!(result[0].file_type(), FileType::Dir);
+
+            // empty directories are part of a download
+            client.get_files("/", &client_local_dir, 1).await.unwrap();
+            assert!(client_local_dir.join("new/empty").is_dir());
+
+            assert!(matches!(
+                client.remove_dir("/new", false).await,
+                Err(Error::RequestFailed(..))
+            ));
+            client.remove_dir("/new", true).await.unwrap();
+            assert!(!client_remote_dir.join("new").exists());
+            client.shutdown().await.unwrap();
+        });
+
+        for result in futures::future::join_all(vec![server, client]).await {
+            result.unwrap();
+        }
+
+        fs::remove_dir_all(&remote_dir).unwrap();
+        fs::remove_dir_all(&local_dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn successful_file_operations() {
+        let remote_dir = std::env::temp_dir().join("qftp_successful_file_operations");
+        let _ = fs::remove_dir_all(&remote_dir);
+        fs::create_dir_all(remote_dir.join("dir")).unwrap();
+        fs::write(remote_dir.join("file"), "content").unwrap();
+
+        let server_remote_dir = remote_dir.clone();
+        let server = tokio::spawn(async move {
+            let server = new_server(2349, server_remote_dir).await;
+            let mut connected_client = server.accept().await.unwrap();
+            for _ in 0..5 {
+                connected_client
+                    .next_request()
+                    .await
+                    .expect("next request returned err");
+            }
+            connected_client.shutdown().await.unwrap();
+        });
+
+        let client_remote_dir = remote_dir.clone();
+        let client = tokio::spawn(async move {
+            let client_config = QClientConfig::dangerous_dont_verify();
+            let client = Client::builder()
+                .set_addr("127.0.0.1:2349", "dev.local".to_string())
+                .with_client_config(client_config.into())
+                .with_credentials("test_user".to_string(), "123".to_string())
+                .build()
+                .await
+                .expect("error constructing the client");
+            client.rename("/file", "renamed").await.unwrap();
+            assert!(client_remote_dir.join("renamed").is_file());
+
+            client.move_path("/renamed", "/dir").await.unwrap();
+            assert!(client_remote_dir.join("dir/renamed").is_file());
+
+            assert!(matches!(
+                client.move_path("/dir/renamed", "/../escaped").await,
+                Err(Error::RequestFailed(ErrorCode::InvalidPath, _))
+            ));
+
+            client.remove("/dir/renamed").await.unwrap();
+            assert!(!client_remote_dir.join("dir/renamed").exists());
+            assert!(matches!(
+                client.remove("/dir").await,
+                Err(Error::RequestFailed(..))
+            ));
+            client.shutdown().await.unwrap();
+        });
+
+        for result in futures::future::join_all(vec![server, client]).await {
+            result.unwrap();
+        }
+
+        fs::remove_dir_all(&remote_dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn successful_concurrent_requests() {
+        let remote_dir = std::env::temp_dir().join("qftp_successful_concurrent_requests");
+        let local_dir = std::env::temp_dir().join("qftp_successful_concurrent_requests_local");
+        let _ = fs::remove_dir_all(&remote_dir);
+        let _ = fs::remove_dir_all(&local_dir);
+        fs::create_dir_all(remote_dir.join("dir")).unwrap();
+        fs::write(remote_dir.join("dir/file"), "content").unwrap();
+
+        let server_remote_dir = remote_dir.clone();
+        let server = tokio::spawn(async move {
+            let server = new_server(2351, server_remote_dir).await;
+            let mut connected_client = server.accept().await.unwrap();
+            for _ in 0..5 {
+                connected_client
+                    .next_request()
+                    .await
+                    .expect("next request returned err");
+            }
+            connected_client.shutdown().await.unwrap();
+        });
+
+        let client_remote_dir = remote_dir.clone();
+        let client_local_dir = local_dir.clone();
+        let client = tokio::spawn(async move {
+            let client_config = QClientConfig::dangerous_dont_verify();
+            let client = Client::builder()
+                .set_addr("127.0.0.1:2351", "dev.local".to_string())
+                .with_client_config(client_config.into())
+                .with_credentials("test_user".to_string(), "123".to_string())
+                .build()
+                .await
+                .expect("error constructing the client");
+
+            // the transfers run on their own clones of the client, while the client itself is used concurrently
+            let list = tokio::spawn({
+                let client = client.clone();
+                async move { client.list_files("/dir", None).await }
+            });
+            let get_first = tokio::spawn({
+                let client = client.clone();
+                let local_dir = client_local_dir.join("first");
+                async move { client.get_files("/", local_dir, 2).await }
+            });
+            let get_second = tokio::spawn({
+                let client = client.clone();
+                let local_dir = client_local_dir.join("second");
+                async move { client.get_files("/dir", local_dir, 1).await }
+            });
+            let (make_dir, remove) =
+                tokio::join!(client.make_dir("/new"), client.remove("/missing"));
+
+            let list = list.await.unwrap();
+            let get_first = get_first.await.unwrap();
+            let get_second = get_second.await.unwrap();
+            assert_eq!(list.unwrap().len(), 1);
+            get_first.unwrap();
+            get_second.unwrap();
+            make_dir.unwrap();
+            assert!(client_remote_dir.join("new").is_dir());
+            assert!(matches!(
+                remove,
+                Err(Error::RequestFailed(ErrorCode::NotFound, _))
+            ));
+            client.shutdown().await.unwrap();
+        });
+
+        for result in futures::future::join_all(vec![server, client]).await {
+            result.unwrap();
+        }
+
+        assert_eq!(
+            fs::read_to_string(local_dir.join("first/dir/file")).unwrap(),
+            "content"
+        );
+        assert_eq!(
+            fs::read_to_string(local_dir.join("second/dir/file")).unwrap(),
+            "content"
+        );
+        fs::remove_dir_all(&remote_dir).unwrap();
+        fs::remove_dir_all(&local_dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn failed_requests() {
+        let local_dir = std::env::temp_dir().join("qftp_failed_requests");
+        let _ = fs::remove_dir_all(&local_dir);
+
+        let server = tokio::spawn(async {
+            let server = new_default_server(2352).await;
+            let mut connected_client = server.accept().await.unwrap();
+            for _ in 0..5 {
+                connected_client
+                    .next_request()
+                    .await
+                    .expect("next request returned err");
+            }
+            connected_client.shutdown().await.unwrap();
+        });
+
+        let client_local_dir = local_dir.clone();
+        let client = tokio::spawn(async move {
+            let client_config = QClientConfig::dangerous_dont_verify();
+            let client = Client::builder()
+                .set_addr("127.0.0.1:2352", "dev.local".to_string())
+                .with_client_config(client_config.into())
+                .with_credentials("test_user".to_string(), "123".to_string())
+                .build()
+                .await
+                .expect("error constructing the client");
+
+            // every failed request is answered with an error instead of leaving the client waiting
+            assert!(matches!(
+                client.list_files("/missing", None).await,
+                Err(Error::RequestFailed(ErrorCode::NotFound, _))
+            ));
+            assert!(matches!(
+                client.get_files("/../escaped", &client_local_dir, 2).await,
+                Err(Error::RequestFailed(ErrorCode::InvalidPath, _))
+            ));
+            let walk_dir = format!("{}/tests/walk_dir", env!("CARGO_MANIFEST_DIR"));
+            assert!(matches!(
+                client.put_files(&walk_dir, "/../escaped", 2).await,
+                Err(Error::RequestFailed(ErrorCode::InvalidPath, _))
+            ));
+            // more streams than the server allows per request
+            assert!(matches!(
+                client.get_files("/", &client_local_dir, 65).await,
+                Err(Error::RequestFailed(ErrorCode::TooManyStreams, _))
+            ));
+            assert!(matches!(
+                client.put_files(&walk_dir, "/uploaded", 65).await,
+                Err(Error::RequestFailed(ErrorCode::TooManyStreams, _))
+            ));
+            client.shutdown().await.unwrap();
+        });
+
+        for result in futures::future::join_all(vec![server, client]).await {
+            result.unwrap();
+        }
+
+        assert!(!local_dir.exists());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn failed_version_negotiation() {
+        let server = tokio::spawn(async {
+            let server = new_default_server(2353).await;
+            assert!(matches!(
+                server.accept().await,
+                Err(Error::NegotiationError)
+            ));
+        });
+
+        let client = tokio::spawn(async {
+            // a raw connection, since the Client only offers supported versions
+            let client_config: rustls::ClientConfig = QClientConfig::dangerous_dont_verify().into();
+            let mut endpoint = quinn::Endpoint::client("0.0.0.0:0".parse().unwrap()).unwrap();
+            endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(client_config)));
+            let connection = endpoint
+                .connect("127.0.0.1:2353".parse().unwrap(), "dev.local")
+                .unwrap()
+                .await
+                .unwrap();
+
+            let (mut send, recv) = connection.open_bi().await.unwrap();
+            // a Version message only containing version 2
+            send.write_all(&[1, 2]).await.unwrap();
+            let response = recv.read_to_end(1024).await.unwrap();
+            // an ERROR message for request 0 with the error code UNSUPPORTED_VERSION
+            assert_eq!(&response[..6], &[0x02, 0, 0, 0, 0, 5]);
+
+            match connection.closed().await {
+                quinn::ConnectionError::ApplicationClosed(close) => {
+                    assert_eq!(close.error_code, CloseCode::VersionMismatch.into())
+                }
+                e => panic!("connection wasn't closed by the server: {e:?}"),
+            }
+        });
+
+        for result in futures::future::join_all(vec![server, client]).await {
+            result.unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn failed_login() {
+        let server = tokio::spawn(async {
+            let server = new_default_server(2354).await;
+            for _ in 0..2 {
+                assert!(matches!(
+                    server.accept().await,
+                    Err(Error::AuthenticationError(_))
+                ));
+            }
+        });
+
+        let client = tokio::spawn(async {
+            let mut reasons = Vec::new();
+            for (name, password) in [("test_user", "wrong"), ("unknown_user", "123")] {
+                let client_config = QClientConfig::dangerous_dont_verify();
+                let result = Client::builder()
+                    .set_addr("127.0.0.1:2354", "dev.local".to_string())
+                    .with_client_config(client_config.into())
+                    .with_credentials(name.to_string(), password.to_string())
+                    .build()
+                    .await;
+                match result {
+                    Err(Error::LoginError(reason)) => reasons.push(reason),
+                    r => panic!("login didn't fail: {r:?}"),
+                }
+            }
+            // the reason doesn't tell whether the user exists
+            assert_eq!(reasons[0], reasons[1]);
+            assert!(!reasons[0].is_empty());
+        });
+
+        for result in futures::future::join_all(vec![server, client]).await {
+            result.unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn locked_out_login() {
+        let path = format!("{}/tests/walk_dir", env!("CARGO_MANIFEST_DIR"));
+        let policy = LockoutPolicy {
+            max_user_failures: 1,
+            lockout: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let server = server_builder(2361, PathBuf::from(path))
+            .set_lockout_policy(policy)
+            .build()
+            .await
+            .unwrap();
+        let server = tokio::spawn(async move {
+            assert!(matches!(
+                server.accept().await,
+                Err(Error::AuthenticationError(AuthError::WrongPassword))
+            ));
+            assert!(matches!(
+                server.accept().await,
+                Err(Error::AuthenticationError(AuthError::LockedOut(_)))
+            ));
+        });
+
+        let client = tokio::spawn(async {
+            let mut reasons = Vec::new();
+            // the right password is rejected as well once the user is locked out
+            for password in ["wrong", "123"] {
+                let client_config = QClientConfig::dangerous_dont_verify();
+                let result = Client::builder()
+                    .set_addr("127.0.0.1:2361", "dev.local".to_string())
+                    .with_client_config(client_config.into())
+                    .with_credentials("test_user".to_string(), password.to_string())
+                    .build()
+                    .await;
+                match result {
+                    Err(Error::LoginError(reason)) => reasons.push(reason),
+                    r => panic!("login didn't fail: {r:?}"),
+                }
+            }
+            assert_ne!(reasons[0], reasons[1]);
+        });
+
+        for result in futures::future::join_all(vec![server, client]).await {
+            result.unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn successful_serve() {
+        let path = format!("{}/tests/walk_dir", env!("CARGO_MANIFEST_DIR"));
+        let server = server_builder(2355, PathBuf::from(path))
+            .set_max_connections(2)
+            .build()
+            .await
+            .unwrap();
+        let server = Arc::new(server);
+        let serve = tokio::spawn({
+            let server = server.clone();
+            async move { server.serve().await }
+        });
+
+        // both clients are handled at the same time and can send any number of requests
+        let (first, second) = tokio::join!(new_client(2355), new_client(2355));
+        let (first, second) = (first.unwrap(), second.unwrap());
+        for _ in 0..3 {
+            let (first, second) =
+                tokio::join!(first.list_files("/", None), second.list_files("/b", None));
+            assert_eq!(first.unwrap().len(), 7);
+            assert_eq!(second.unwrap().len(), 3);
+        }
+
+        // the limit is reached, so the next client is refused
+        assert!(matches!(
+            new_client(2355).await,
+            Err(Error::ConnectionError(_))
+        ));
+
+        first.shutdown().await.unwrap();
+        second.shutdown().await.unwrap();
+        server.close();
+        serve.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn cancelled_get_files() {
+        let remote_dir = std::env::temp_dir().join("qftp_cancelled_get_files_remote");
+        let local_dir = std::env::temp_dir().join("qftp_cancelled_get_files_local");
+        let _ = fs::remove_dir_all(&remote_dir);
+        let _ = fs::remove_dir_all(&local_dir);
+        fs::create_dir(&remote_dir).unwrap();
+        // large enough that the download is still running when it's cancelled
+        fs::File::create(remote_dir.join("large.bin"))
+            .unwrap()
+            .set_len(64 << 20)
+            .unwrap();
+
+        let server_remote_dir = remote_dir.clone();
+        let server = tokio::spawn(async move {
+            let server = new_server(2356, server_remote_dir).await;
+            let mut connected_client = server.accept().await.unwrap();
+            // the cancelled get, the CancelRequest, the list and the second get
+            for _ in 0..4 {
+                connected_client
+                    .next_request()
+                    .await
+                    .expect("next request returned err");
+            }
+            connected_client.shutdown().await.unwrap();
+        });
+
+        let client_local_dir = local_dir.clone();
+        let client = tokio::spawn(async move {
+            let client = new_client(2356).await.unwrap();
+            // dropping the download cancels it
+            let download = client.get_files("/", &client_local_dir, 1);
+            assert!(tokio::time::timeout(Duration::from_millis(50), download)
+                .await
+                .is_err());
+
+            // the connection can still be used afterwards
+            let files = client.list_files("/", None).await.unwrap();
+            assert_eq!(files.len(), 1);
+            client.get_files("/", &client_local_dir, 1).await.unwrap();
+            client.shutdown().await.unwrap();
+        });
+
+        for result in futures::future::join_all(vec![server, client]).await {
+            result.unwrap();
+        }
+
+        assert_dirs_equal(&remote_dir, &local_dir);
+        fs::remove_dir_all(&remote_dir).unwrap();
+        fs::remove_dir_all(&local_dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn cancelled_put_files() {
+        let remote_dir = std::env::temp_dir().join("qftp_cancelled_put_files_remote");
+        let local_dir = std::env::temp_dir().join("qftp_cancelled_put_files_local");
+        let _ = fs::remove_dir_all(&remote_dir);
+        let _ = fs::remove_dir_all(&local_dir);
+        fs::create_dir(&remote_dir).unwrap();
+        fs::create_dir(&local_dir).unwrap();
+        // large enough that the upload is still running when it's cancelled
+        fs::File::create(local_dir.join("large.bin"))
+            .unwrap()
+            .set_len(64 << 20)
+            .unwrap();
+
+        let server_remote_dir = remote_dir.clone();
+        let server = tokio::spawn(async move {
+            let server = new_server(2362, server_remote_dir).await;
+            let mut connected_client = server.accept().await.unwrap();
+            // the cancelled put, the CancelRequest and the second put
+            for _ in 0..3 {
+                connected_client
+                    .next_request()
+                    .await
+                    .expect("next request returned err");
+            }
+            connected_client.shutdown().await.unwrap();
+        });
+
+        let client_local_dir = local_dir.clone();
+        let client = tokio::spawn(async move {
+            let client = new_client(2362).await.unwrap();
+            // dropping the upload cancels it and resets its streams
+            let upload = client.put_files(&client_local_dir, "/uploaded", 1);
+            assert!(tokio::time::timeout(Duration::from_millis(50), upload)
+                .await
+                .is_err());
+
+            // the connection can still be used afterwards
+            client
+                .put_files(&client_local_dir, "/uploaded", 1)
+                .await
+                .unwrap();
+            client.shutdown().await.unwrap();
+        });
+
+        for result in futures::future::join_all(vec![server, client]).await {
+            result.unwrap();
+        }
+
+        assert_dirs_equal(&local_dir, &remote_dir.join("uploaded"));
+        fs::remove_dir_all(&remote_dir).unwrap();
+        fs::remove_dir_all(&local_dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn successful_user_root() {
+        let auth_file = std::env::temp_dir().join("qftp_successful_user_root.json");
+        let _ = fs::remove_file(&auth_file);
+        let storage = FileStorage::new(&auth_file).await.unwrap();
+        AuthManager::new(storage)
+            .add_user(
+                "team_b".to_string(),
+                "123".to_string(),
+                501,
+                vec![20],
+                Some(PathBuf::from("b")),
+            )
+            .await
+            .unwrap();
+
+        let path = format!("{}/tests/walk_dir", env!("CARGO_MANIFEST_DIR"));
+        let server_auth_file = auth_file.clone();
+        let server = tokio::spawn(async move {
+            let server = server_builder(2357, PathBuf::from(path))
+                .set_auth_file(server_auth_file)
+                .build()
+                .await
+                .unwrap();
+            let mut connected_client = server.accept().await.unwrap();
+            for _ in 0..2 {
+                connected_client
+                    .next_request()
+                    .await
+                    .expect("next request returned err");
+            }
+            connected_client.shutdown().await.unwrap();
+        });
+
+        let client = tokio::spawn(async {
+            let client_config = QClientConfig::dangerous_dont_verify();
+            let client = Client::builder()
+                .set_addr("127.0.0.1:2357", "dev.local".to_string())
+                .with_client_config(client_config.into())
+                .with_credentials("team_b".to_string(), "123".to_string())
+                .build()
+                .await
+                .expect("error constructing the client");
+
+            // the user only sees the contents of its root
+            let files = client.list_files("/", None).await.unwrap();
+            let mut paths: Vec<&str> = files.iter().map(|file| file.file_name()).collect();
+            paths.sort();
+            assert_eq!(paths, ["b.txt", "c", "c/c.txt"]);
+            assert!(matches!(
+                client.list_files("/../a", None).await,
+                Err(Error::RequestFailed(ErrorCode::InvalidPath, _))
+            ));
+            client.shutdown().await.unwrap();
+        });
+
+        for result in futures::future::join_all(vec![server, client]).await {
+            result.unwrap();
+        }
+
+        fs::remove_file(&auth_file).unwrap();
+        fs::remove_file(auth_file.with_extension("json.lock")).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn successful_client_cert_login() {
+        let client_cert = Certificate(fs::read("cert/client.crt.der").unwrap());
+        let client_key = PrivateKey(fs::read("cert/client.key.der").unwrap());
+        let client_ca = Certificate(fs::read("cert/dev_ca.crt.der").unwrap());
+        let client_config = || {
+            QClientConfig::dangerous_dont_verify()
+                .with_client_cert(vec![client_cert.clone()], client_key.clone())
+                .unwrap()
+        };
+
+        let path = PathBuf::from(format!("{}/tests/walk_dir", env!("CARGO_MANIFEST_DIR")));
+        let (cert, priv_key) = read_test_certs();
+        let fingerprint = certificate_fingerprint(&client_cert);
+        let server = server_builder(2358, path.clone())
+            .with_storage(InMemoryStorage::from(test_users(
+                vec![fingerprint],
+                Vec::new(),
+            )))
+            .with_client_auth(
+                vec![cert.clone()],
+                priv_key.clone(),
+                vec![client_ca.clone()],
+            )
+            .build()
+            .await
+            .unwrap();
+        let server = Arc::new(server);
+        let serve = tokio::spawn({
+            let server = server.clone();
+            async move { server.serve().await }
+        });
+
+        // the certificate logs the client in as test_user without any credentials
+        let client = Client::builder()
+            .set_addr("127.0.0.1:2358", "dev.local".to_string())
+            .with_client_config(client_config().into())
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(client.login_method(), LoginMethod::Certificate);
+        assert_eq!(client.list_files("/b", None).await.unwrap().len(), 3);
+        client.shutdown().await.unwrap();
+
+        // the second connection resumes the TLS session of the first one, so the client isn't asked
+        // for its certificate again. The server still knows it from the first handshake.
+        // Without credentials the login can't fall back to the password
+        let shared_config: ClientConfig = client_config().into();
+        for _ in 0..2 {
+            let client = Client::builder()
+                .set_addr("127.0.0.1:2358", "dev.local".to_string())
+                .with_client_config(shared_config.clone())
+                .build()
+                .await
+                .unwrap();
+            assert_eq!(client.login_method(), LoginMethod::Certificate);
+            assert_eq!(client.list_files("/b", None).await.unwrap().len(), 3);
+            client.shutdown().await.unwrap();
+        }
+
+        // clients without a certificate still log in with their password
+        let client = new_client(2358).await.unwrap();
+        assert_eq!(client.login_method(), LoginMethod::Password);
+        assert_eq!(client.list_files("/b", None).await.unwrap().len(), 3);
+        client.shutdown().await.unwrap();
+        let result = Client::builder()
+            .set_addr("127.0.0.1:2358", "dev.local".to_string())
+            .with_client_config(QClientConfig::dangerous_dont_verify().into())
+            .build()
+            .await;
+        assert!(matches!(result, Err(Error::CredentialError(_))));
+
+        server.close();
+        serve.await.unwrap().unwrap();
+
+        // a certificate that isn't mapped to a user fails the login
+        let server = server_builder(2359, path)
+            .with_client_auth(vec![cert], priv_key, vec![client_ca])
+            .build()
+            .await
+            .unwrap();
+        let server = tokio::spawn(async move {
+            assert!(matches!(
+                server.accept().await,
+                Err(Error::AuthenticationError(AuthError::UserNotFound))
+            ));
+        });
+        let result = Client::builder()
+            .set_addr("127.0.0.1:2359", "dev.local".to_string())
+            .with_client_config(client_config().into())
+            .with_credentials("test_user".to_string(), "123".to_string())
+            .build()
+            .await;
+        assert!(matches!(result, Err(Error::LoginError(_))));
+        server.await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn successful_key_login() {
+        fn new_key() -> (KeyCredentials, String) {
+            let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+            let key = KeyCredentials::from_pkcs8("test_user".to_string(), pkcs8.as_ref()).unwrap();
+            let public_key = key
+                .public_key()
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect();
+            (key, public_key)
+        }
+        let (key, public_key) = new_key();
+        let (unauthorized_key, _) = new_key();
+
+        let path = format!("{}/tests/walk_dir", env!("CARGO_MANIFEST_DIR"));
+        let server = server_builder(2360, PathBuf::from(path))
+            .with_storage(InMemoryStorage::from(test_users(
+                Vec::new(),
+                vec![public_key],
+            )))
+            .set_login_methods(vec![LoginMethod::PublicKey])
+            .build()
+            .await
+            .unwrap();
+        let server = Arc::new(server);
+        let serve = tokio::spawn({
+            let server = server.clone();
+            async move { server.serve().await }
+        });
+        let builder = || {
+            Client::builder()
+                .set_addr("127.0.0.1:2360", "dev.local".to_string())
+                .with_client_config(QClientConfig::dangerous_dont_verify().into())
+        };
+
+        // the key is preferred, so the password isn't needed
+        for builder in [
+            builder().with_key(key.clone()),
+            builder()
+                .with_key(key)
+                .with_credentials("test_user".to_string(), "wrong".to_string()),
+        ] {
+            let client = builder.build().await.unwrap();
+            assert_eq!(client.list_files("/b", None).await.unwrap().len(), 3);
+            client.shutdown().await.unwrap();
+        }
+
+        // the server doesn't accept passwords
+        let result = builder()
+            .with_credentials("test_user".to_string(), "123".to_string())
+            .build()
+            .await;
+        assert!(matches!(result, Err(Error::LoginError(_))));
+        let result = builder().with_key(unauthorized_key).build().await;
+        assert!(matches!(result, Err(Error::LoginError(_))));
+
+        server.close();
+        serve.await.unwrap().unwrap();
+    }
 }